@@ -0,0 +1,366 @@
+use crate::auth::AuthenticatedAddress;
+use crate::catalog::{now, record_key, Catalog};
+use serde::{Deserialize, Serialize};
+use std::io;
+use uuid::Uuid;
+
+/// What a grant lets its holder do with a dataset.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Operation {
+    Total,
+    Average,
+    Min,
+    Max,
+    Count,
+    /// Comparing the total to a threshold, or counting the rows that do.
+    Compare,
+    /// Comparing every row to a threshold, which reveals far more than
+    /// `Compare`.
+    CompareRows,
+    Variance,
+    Histogram,
+    Download,
+}
+
+/// Access to one dataset the owner gave to another address.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Grant {
+    pub grant_id: String,
+    pub owner: String,
+    pub file_id: String,
+    /// Lowercase address of the holder.
+    pub grantee: String,
+    pub operations: Vec<Operation>,
+    pub expires_at: Option<u64>,
+    pub max_queries: Option<u64>,
+    /// Operations used so far, counted against `max_queries`.
+    pub queries: u64,
+    pub created_at: u64,
+    pub revoked_at: Option<u64>,
+}
+
+impl Grant {
+    pub fn new(
+        owner: &str,
+        file_id: &str,
+        grantee: &str,
+        operations: Vec<Operation>,
+        expires_at: Option<u64>,
+        max_queries: Option<u64>,
+    ) -> Self {
+        Grant {
+            grant_id: Uuid::new_v4().to_string(),
+            owner: owner.to_string(),
+            file_id: file_id.to_string(),
+            grantee: grantee.to_lowercase(),
+            operations,
+            expires_at,
+            max_queries,
+            queries: 0,
+            created_at: now(),
+            revoked_at: None,
+        }
+    }
+
+    /// Why the grant does not allow `operation` right now, if it does not.
+    fn denial(&self, operation: Operation) -> Option<&'static str> {
+        if self.revoked_at.is_some() {
+            Some("Access was revoked")
+        } else if self
+            .expires_at
+            .is_some_and(|expires_at| expires_at <= now())
+        {
+            Some("Access expired")
+        } else if !self.operations.contains(&operation) {
+            Some("Operation not granted")
+        } else if self
+            .max_queries
+            .is_some_and(|max_queries| self.queries >= max_queries)
+        {
+            Some("Query limit reached")
+        } else {
+            None
+        }
+    }
+}
+
+/// Grants of every dataset, kept in the catalog database. There is at most
+/// one grant per dataset and grantee; granting again replaces it.
+#[derive(Clone)]
+pub struct AccessControl {
+    grants: sled::Tree,
+}
+
+impl AccessControl {
+    pub fn open(catalog: &Catalog) -> Result<Self, String> {
+        Ok(AccessControl {
+            grants: catalog.tree("grants")?,
+        })
+    }
+
+    pub fn grant(&self, grant: &Grant) -> io::Result<()> {
+        self.grants
+            .insert(
+                grant_key(&grant.owner, &grant.file_id, &grant.grantee),
+                serde_json::to_vec(grant)?,
+            )
+            .map_err(to_io)?;
+        self.grants.flush().map_err(to_io)?;
+        Ok(())
+    }
+
+    /// Every grant of a dataset, revoked ones included.
+    pub fn list(&self, owner: &str, file_id: &str) -> io::Result<Vec<Grant>> {
+        let prefix = format!("{}/", record_key(owner, file_id));
+        let mut grants = Vec::new();
+        for item in self.grants.scan_prefix(prefix.as_bytes()) {
+            let (_, value) = item.map_err(to_io)?;
+            grants.push(serde_json::from_slice(&value)?);
+        }
+        Ok(grants)
+    }
+
+    /// Marks the grant revoked, keeping it so the owner can still see it.
+    pub fn revoke(&self, owner: &str, file_id: &str, grantee: &str) -> io::Result<Option<Grant>> {
+        let key = grant_key(owner, file_id, grantee);
+        let mut grant: Grant = match self.grants.get(&key).map_err(to_io)? {
+            Some(value) => serde_json::from_slice(&value)?,
+            None => return Ok(None),
+        };
+        if grant.revoked_at.is_none() {
+            grant.revoked_at = Some(now());
+            self.grant(&grant)?;
+        }
+        Ok(Some(grant))
+    }
+
+    /// Lets the owner through, and anyone holding a live grant for
+    /// `operation`, whose use is counted against the grant's query limit.
+    pub fn authorize(
+        &self,
+        owner: &str,
+        file_id: &str,
+        caller: &AuthenticatedAddress,
+        operation: Operation,
+    ) -> io::Result<()> {
+        if caller.is(owner) {
+            return Ok(());
+        }
+        let key = grant_key(owner, file_id, &caller.0);
+        // Check and count in one step so concurrent queries cannot overrun the limit
+        let previous = self
+            .grants
+            .fetch_and_update(&key, |value| {
+                let value = value?;
+                match serde_json::from_slice::<Grant>(value) {
+                    Ok(mut grant) if grant.denial(operation).is_none() => {
+                        grant.queries += 1;
+                        serde_json::to_vec(&grant).ok()
+                    }
+                    _ => Some(value.to_vec()),
+                }
+            })
+            .map_err(to_io)?;
+        let denial = match previous {
+            Some(value) => serde_json::from_slice::<Grant>(&value)?.denial(operation),
+            None => Some("No access granted"),
+        };
+        match denial {
+            Some(reason) => {
+                log::warn!(
+                    "{} was denied {:?} on {}/{}: {}",
+                    caller.0,
+                    operation,
+                    owner,
+                    file_id,
+                    reason
+                );
+                Err(io::Error::new(io::ErrorKind::PermissionDenied, reason))
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Gives back the query `authorize` counted when the operation it let
+    /// through failed, so grantees only pay for results they got.
+    pub fn refund(
+        &self,
+        owner: &str,
+        file_id: &str,
+        caller: &AuthenticatedAddress,
+    ) -> io::Result<()> {
+        if caller.is(owner) {
+            return Ok(());
+        }
+        self.grants
+            .fetch_and_update(grant_key(owner, file_id, &caller.0), |value| {
+                let value = value?;
+                match serde_json::from_slice::<Grant>(value) {
+                    Ok(mut grant) => {
+                        grant.queries = grant.queries.saturating_sub(1);
+                        serde_json::to_vec(&grant).ok()
+                    }
+                    Err(_) => Some(value.to_vec()),
+                }
+            })
+            .map_err(to_io)?;
+        Ok(())
+    }
+}
+
+fn grant_key(owner: &str, file_id: &str, grantee: &str) -> String {
+    format!("{}/{}", record_key(owner, file_id), grantee.to_lowercase())
+}
+
+fn to_io(err: sled::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, err)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OWNER: &str = "0xf39fd6e51aad88f6f4ce6ab8827279cfffb92266";
+    const GRANTEE: &str = "0x70997970c51812dc3a010c7d01b50e0d17dc79c8";
+
+    fn setup() -> (tempfile::TempDir, AccessControl) {
+        let dir = tempfile::tempdir().unwrap();
+        let catalog = Catalog::open(&dir.path().join("catalog")).unwrap();
+        let access = AccessControl::open(&catalog).unwrap();
+        (dir, access)
+    }
+
+    fn caller(address: &str) -> AuthenticatedAddress {
+        AuthenticatedAddress(address.to_string())
+    }
+
+    fn denial(result: io::Result<()>) -> String {
+        let err = result.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+        err.to_string()
+    }
+
+    #[test]
+    fn lets_the_owner_through_without_a_grant() {
+        let (_dir, access) = setup();
+        for _ in 0..3 {
+            access
+                .authorize(OWNER, "data", &caller(OWNER), Operation::CompareRows)
+                .unwrap();
+        }
+        assert!(access.list(OWNER, "data").unwrap().is_empty());
+    }
+
+    #[test]
+    fn denies_callers_without_a_grant() {
+        let (_dir, access) = setup();
+        let result = access.authorize(OWNER, "data", &caller(GRANTEE), Operation::Total);
+        assert_eq!(denial(result), "No access granted");
+    }
+
+    #[test]
+    fn denies_operations_that_were_not_granted() {
+        let (_dir, access) = setup();
+        let grant = Grant::new(OWNER, "data", GRANTEE, vec![Operation::Total], None, None);
+        access.grant(&grant).unwrap();
+        access
+            .authorize(OWNER, "data", &caller(GRANTEE), Operation::Total)
+            .unwrap();
+        let result = access.authorize(OWNER, "data", &caller(GRANTEE), Operation::CompareRows);
+        assert_eq!(denial(result), "Operation not granted");
+    }
+
+    #[test]
+    fn denies_expired_grants() {
+        let (_dir, access) = setup();
+        let expired = Grant::new(
+            OWNER,
+            "data",
+            GRANTEE,
+            vec![Operation::Total],
+            Some(now()),
+            None,
+        );
+        access.grant(&expired).unwrap();
+        let result = access.authorize(OWNER, "data", &caller(GRANTEE), Operation::Total);
+        assert_eq!(denial(result), "Access expired");
+
+        let live = Grant::new(
+            OWNER,
+            "data",
+            GRANTEE,
+            vec![Operation::Total],
+            Some(now() + 3600),
+            None,
+        );
+        access.grant(&live).unwrap();
+        access
+            .authorize(OWNER, "data", &caller(GRANTEE), Operation::Total)
+            .unwrap();
+    }
+
+    #[test]
+    fn counts_queries_against_the_limit_and_refunds_failures() {
+        let (_dir, access) = setup();
+        let grant = Grant::new(
+            OWNER,
+            "data",
+            GRANTEE,
+            vec![Operation::Total],
+            None,
+            Some(2),
+        );
+        access.grant(&grant).unwrap();
+        let grantee = caller(GRANTEE);
+        for _ in 0..2 {
+            access
+                .authorize(OWNER, "data", &grantee, Operation::Total)
+                .unwrap();
+        }
+        let result = access.authorize(OWNER, "data", &grantee, Operation::Total);
+        assert_eq!(denial(result), "Query limit reached");
+        assert_eq!(access.list(OWNER, "data").unwrap()[0].queries, 2);
+
+        // A failed operation gives its query back
+        access.refund(OWNER, "data", &grantee).unwrap();
+        assert_eq!(access.list(OWNER, "data").unwrap()[0].queries, 1);
+        access
+            .authorize(OWNER, "data", &grantee, Operation::Total)
+            .unwrap();
+        assert_eq!(access.list(OWNER, "data").unwrap()[0].queries, 2);
+    }
+
+    #[test]
+    fn refund_never_goes_below_zero() {
+        let (_dir, access) = setup();
+        let grant = Grant::new(
+            OWNER,
+            "data",
+            GRANTEE,
+            vec![Operation::Total],
+            None,
+            Some(1),
+        );
+        access.grant(&grant).unwrap();
+        access.refund(OWNER, "data", &caller(GRANTEE)).unwrap();
+        assert_eq!(access.list(OWNER, "data").unwrap()[0].queries, 0);
+    }
+
+    #[test]
+    fn denies_revoked_grants_and_keeps_them_listed() {
+        let (_dir, access) = setup();
+        let grant = Grant::new(OWNER, "data", GRANTEE, vec![Operation::Total], None, None);
+        access.grant(&grant).unwrap();
+        // Grantees are matched whatever the case of their address
+        let revoked = access
+            .revoke(OWNER, "data", &GRANTEE.to_uppercase().replace("0X", "0x"))
+            .unwrap()
+            .unwrap();
+        assert!(revoked.revoked_at.is_some());
+        let result = access.authorize(OWNER, "data", &caller(GRANTEE), Operation::Total);
+        assert_eq!(denial(result), "Access was revoked");
+        assert_eq!(access.list(OWNER, "data").unwrap().len(), 1);
+        assert!(access.revoke(OWNER, "other", GRANTEE).unwrap().is_none());
+    }
+}
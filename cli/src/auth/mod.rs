@@ -0,0 +1,252 @@
+use k256::ecdsa::{RecoveryId, Signature, VerifyingKey};
+use lazy_static::lazy_static;
+use rand::Rng;
+use rocket::http::Status;
+use rocket::request::{FromRequest, Outcome};
+use rocket::{get, Request};
+use serde::Serialize;
+use sha3::{Digest, Keccak256};
+use std::collections::HashMap;
+use std::io;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// How long a challenge can be signed and used for, in seconds.
+const CHALLENGE_TTL: u64 = 5 * 60;
+/// Unused challenges kept at once, so unauthenticated callers cannot grow the
+/// map without bound.
+const MAX_CHALLENGES: usize = 10_000;
+/// Unused challenges kept per address, the oldest is dropped past it.
+const MAX_CHALLENGES_PER_ADDRESS: usize = 5;
+
+lazy_static! {
+    static ref CHALLENGES: Mutex<HashMap<String, Challenge>> = Mutex::new(HashMap::new());
+}
+
+struct Challenge {
+    address: String,
+    message: String,
+    expires_at: u64,
+}
+
+#[derive(Serialize)]
+struct ChallengeResult {
+    nonce: String,
+    message: String,
+    expires_at: u64,
+}
+
+/// Issues a single use challenge that the holder of `address` signs with
+/// `personal_sign` (EIP-191) and sends back through the `x-zen-*` headers.
+#[get("/auth/nonce/<address>")]
+pub async fn nonce_handler(address: String) -> Result<String, io::Error> {
+    if parse_address(&address).is_none() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Invalid ethereum address",
+        ));
+    }
+    let nonce = hex::encode(rand::thread_rng().gen::<[u8; 16]>());
+    let expires_at = now() + CHALLENGE_TTL;
+    let message = format!(
+        "Datazen wants you to sign in with your Ethereum account:\n{}\n\nNonce: {}\nExpires At: {}",
+        address.to_lowercase(),
+        nonce,
+        expires_at
+    );
+
+    let mut challenges = CHALLENGES.lock().unwrap();
+    let current = now();
+    challenges.retain(|_, challenge| challenge.expires_at > current);
+    let mut pending: Vec<(&String, u64)> = challenges
+        .iter()
+        .filter(|(_, challenge)| challenge.address.eq_ignore_ascii_case(&address))
+        .map(|(nonce, challenge)| (nonce, challenge.expires_at))
+        .collect();
+    if pending.len() >= MAX_CHALLENGES_PER_ADDRESS {
+        pending.sort_by_key(|(_, expires_at)| *expires_at);
+        let oldest = pending[0].0.clone();
+        challenges.remove(&oldest);
+    } else if challenges.len() >= MAX_CHALLENGES {
+        log::warn!("Refused a challenge for {}, too many pending", address);
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            "Too many pending challenges, try again later",
+        ));
+    }
+    challenges.insert(
+        nonce.clone(),
+        Challenge {
+            address: address.to_lowercase(),
+            message: message.clone(),
+            expires_at,
+        },
+    );
+
+    let res = ChallengeResult {
+        nonce,
+        message,
+        expires_at,
+    };
+    Ok(serde_json::to_string(&res).unwrap())
+}
+
+/// Lowercase `0x` address of a caller that proved ownership of it by signing
+/// a challenge from `/auth/nonce/<address>`.
+pub struct AuthenticatedAddress(pub String);
+
+impl AuthenticatedAddress {
+    pub fn is(&self, address: &str) -> bool {
+        self.0.eq_ignore_ascii_case(address)
+    }
+
+    /// Errors unless the caller is `address`, for handlers acting on behalf of
+    /// the address named in their payload. Returns the address lowercased, the
+    /// spelling paths, catalog keys and grants are stored under.
+    pub fn ensure(&self, address: &str) -> Result<&str, io::Error> {
+        if self.is(address) {
+            Ok(&self.0)
+        } else {
+            log::warn!("{} tried to act on behalf of {}", self.0, address);
+            Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "Signer does not match the requested address",
+            ))
+        }
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for AuthenticatedAddress {
+    type Error = io::Error;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let headers = request.headers();
+        let (address, nonce, signature) = match (
+            headers.get_one("x-zen-address"),
+            headers.get_one("x-zen-nonce"),
+            headers.get_one("x-zen-signature"),
+        ) {
+            (Some(address), Some(nonce), Some(signature)) => (address, nonce, signature),
+            _ => {
+                return unauthorized("Missing x-zen-address, x-zen-nonce or x-zen-signature header")
+            }
+        };
+
+        // Challenges are consumed on first use, valid or not
+        let challenge = match CHALLENGES.lock().unwrap().remove(nonce) {
+            Some(challenge) => challenge,
+            None => return unauthorized("Unknown or already used nonce"),
+        };
+        if challenge.expires_at < now() {
+            return unauthorized("Challenge expired");
+        }
+        if !challenge.address.eq_ignore_ascii_case(address) {
+            return unauthorized("Challenge was issued for another address");
+        }
+        match recover_signer(&challenge.message, signature) {
+            Some(signer) if signer.eq_ignore_ascii_case(address) => {
+                Outcome::Success(AuthenticatedAddress(signer))
+            }
+            _ => unauthorized("Signature does not match address"),
+        }
+    }
+}
+
+fn unauthorized<T>(reason: &str) -> Outcome<T, io::Error> {
+    log::warn!("Authentication failed: {}", reason);
+    Outcome::Error((
+        Status::Unauthorized,
+        io::Error::new(io::ErrorKind::PermissionDenied, reason.to_string()),
+    ))
+}
+
+/// Recovers the `0x` address that produced an EIP-191 `personal_sign`
+/// signature (65 bytes, hex encoded, `v` as 0/1 or 27/28) over `message`.
+pub fn recover_signer(message: &str, signature: &str) -> Option<String> {
+    let bytes = hex::decode(signature.trim_start_matches("0x")).ok()?;
+    if bytes.len() != 65 {
+        return None;
+    }
+    let signature = Signature::from_slice(&bytes[..64]).ok()?;
+    let v = match bytes[64] {
+        27 | 28 => bytes[64] - 27,
+        v => v,
+    };
+    let recovery_id = RecoveryId::from_byte(v)?;
+
+    let prefixed = format!("\x19Ethereum Signed Message:\n{}{}", message.len(), message);
+    let digest = Keccak256::digest(prefixed.as_bytes());
+    let key = VerifyingKey::recover_from_prehash(&digest, &signature, recovery_id).ok()?;
+    Some(address_of(&key))
+}
+
+fn address_of(key: &VerifyingKey) -> String {
+    let point = key.to_encoded_point(false);
+    let hash = Keccak256::digest(&point.as_bytes()[1..]);
+    format!("0x{}", hex::encode(&hash[12..]))
+}
+
+pub(crate) fn parse_address(address: &str) -> Option<[u8; 20]> {
+    let bytes = hex::decode(address.strip_prefix("0x")?).ok()?;
+    bytes.try_into().ok()
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Hardhat's first development account, whose key is public.
+    const ADDRESS: &str = "0xf39fd6e51aad88f6f4ce6ab8827279cfffb92266";
+    const MESSAGE: &str = "Datazen test vector";
+    /// `personal_sign` of `MESSAGE` by `ADDRESS`.
+    const SIGNATURE: &str = "0xd58f2e700da744eec3323e4b4c8754eef1f6f386c6beff13bb8bc50f8e915139\
+                             53a3a217236e065df6f8b1375a33648589c93869baa50d7c821fb8c23b004a321b";
+
+    #[test]
+    fn recovers_the_signer() {
+        assert_eq!(recover_signer(MESSAGE, SIGNATURE).as_deref(), Some(ADDRESS));
+    }
+
+    #[test]
+    fn accepts_a_zero_based_recovery_id() {
+        let signature = format!("{}00", &SIGNATURE[..SIGNATURE.len() - 2]);
+        assert_eq!(
+            recover_signer(MESSAGE, &signature).as_deref(),
+            Some(ADDRESS)
+        );
+    }
+
+    #[test]
+    fn another_message_recovers_another_address() {
+        let signer = recover_signer("Datazen test vector!", SIGNATURE);
+        assert_ne!(signer.as_deref(), Some(ADDRESS));
+    }
+
+    #[test]
+    fn rejects_malformed_signatures() {
+        assert_eq!(
+            recover_signer(MESSAGE, &SIGNATURE[..SIGNATURE.len() - 2]),
+            None
+        );
+        assert_eq!(recover_signer(MESSAGE, "0xzz"), None);
+    }
+
+    #[test]
+    fn ensure_lowercases_checksummed_addresses() {
+        let signer = AuthenticatedAddress(recover_signer(MESSAGE, SIGNATURE).unwrap());
+        let checksummed = "0xf39Fd6e51aad88F6F4ce6aB8827279cffFb92266";
+        assert_eq!(signer.ensure(checksummed).unwrap(), ADDRESS);
+        assert_eq!(signer.ensure(ADDRESS).unwrap(), ADDRESS);
+        assert!(signer
+            .ensure("0x70997970C51812dc3A010C7d01b50e0d17dc79C8")
+            .is_err());
+    }
+}
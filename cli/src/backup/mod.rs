@@ -0,0 +1,318 @@
+use crate::catalog::{
+    hash_file, now, record_key, BackupReceipt, Catalog, CompanionBackup, DatasetRecord,
+    EncryptionMode, ENVELOPE_FILE,
+};
+use crate::container::FheDataset;
+use crate::fhe_keys;
+use crate::storage::StorageBackend;
+use base64::engine::general_purpose::STANDARD as BASE64_STANDARD;
+use base64::read::DecoderReader;
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Notify;
+use uuid::Uuid;
+
+/// Delay before the first retry, doubled for every further attempt.
+const RETRY_BASE_SECS: u64 = 10;
+const RETRY_MAX_SECS: u64 = 60 * 60;
+/// Attempts before a job is marked failed and left alone.
+const MAX_ATTEMPTS: u32 = 10;
+/// How often the worker looks for due jobs when nothing wakes it up.
+const IDLE_POLL_SECS: u64 = 60;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BackupState {
+    /// Waiting for its first attempt or for the next retry.
+    Pending,
+    Uploading,
+    Pinned,
+    /// Gave up after `MAX_ATTEMPTS`, storing the dataset again re-queues it.
+    Failed,
+}
+
+/// Backup of one dataset, persisted so it survives node restarts.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BackupJob {
+    /// Changes whenever the dataset is stored again, so a stale upload does
+    /// not overwrite the state of the newer job.
+    pub job_id: String,
+    pub dataset_id: String,
+    pub owner: String,
+    pub file_id: String,
+    pub path: String,
+    pub state: BackupState,
+    pub attempts: u32,
+    pub next_attempt_at: u64,
+    pub last_error: Option<String>,
+    pub updated_at: u64,
+}
+
+/// Queue of datasets waiting to be backed up, worked off by [`BackupQueue::run`].
+#[derive(Clone)]
+pub struct BackupQueue {
+    jobs: sled::Tree,
+    wake: Arc<Notify>,
+}
+
+impl BackupQueue {
+    /// Opens the queue kept in the catalog database. Jobs that were uploading
+    /// when the node stopped are retried.
+    pub fn open(catalog: &Catalog) -> Result<Self, String> {
+        let queue = BackupQueue {
+            jobs: catalog.tree("backups")?,
+            wake: Arc::new(Notify::new()),
+        };
+        for mut job in queue
+            .all()
+            .map_err(|err| format!("Failed to read backup queue: {}", err))?
+        {
+            if job.state == BackupState::Uploading {
+                log::info!("Resuming backup of {}/{}", job.owner, job.file_id);
+                job.state = BackupState::Pending;
+                queue
+                    .put(&job)
+                    .map_err(|err| format!("Failed to update backup queue: {}", err))?;
+            }
+        }
+        Ok(queue)
+    }
+
+    /// Queues a backup of `record`'s data file at `path`, replacing any
+    /// earlier job for the same dataset.
+    pub fn enqueue(&self, record: &DatasetRecord, path: &Path) -> io::Result<BackupJob> {
+        let job = BackupJob {
+            job_id: Uuid::new_v4().to_string(),
+            dataset_id: record.id.clone(),
+            owner: record.owner.clone(),
+            file_id: record.file_id.clone(),
+            path: path.to_string_lossy().to_string(),
+            state: BackupState::Pending,
+            attempts: 0,
+            next_attempt_at: now(),
+            last_error: None,
+            updated_at: now(),
+        };
+        self.put(&job)?;
+        self.wake.notify_one();
+        Ok(job)
+    }
+
+    pub fn status(&self, owner: &str, file_id: &str) -> io::Result<Option<BackupJob>> {
+        match self.jobs.get(record_key(owner, file_id)).map_err(to_io)? {
+            Some(value) => Ok(Some(serde_json::from_slice(&value)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn all(&self) -> io::Result<Vec<BackupJob>> {
+        let mut jobs = Vec::new();
+        for item in self.jobs.iter() {
+            let (_, value) = item.map_err(to_io)?;
+            jobs.push(serde_json::from_slice(&value)?);
+        }
+        Ok(jobs)
+    }
+
+    fn put(&self, job: &BackupJob) -> io::Result<()> {
+        self.jobs
+            .insert(
+                record_key(&job.owner, &job.file_id),
+                serde_json::to_vec(job)?,
+            )
+            .map_err(to_io)?;
+        self.jobs.flush().map_err(to_io)?;
+        Ok(())
+    }
+
+    /// Stores the outcome of an attempt unless the dataset was queued again
+    /// in the meantime.
+    fn finish(&self, job: &BackupJob) -> io::Result<bool> {
+        match self.status(&job.owner, &job.file_id)? {
+            Some(current) if current.job_id == job.job_id => {
+                self.put(job)?;
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    /// Uploads due jobs one at a time, forever. Woken up early by `enqueue`.
+    pub async fn run(self, catalog: Catalog, storage: Arc<dyn StorageBackend>) {
+        loop {
+            let next_due = match self.run_due(&catalog, storage.as_ref()).await {
+                Ok(next_due) => next_due,
+                Err(err) => {
+                    log::error!("Backup queue failed 😭. Error: {}", err);
+                    None
+                }
+            };
+            let wait = next_due
+                .map(|due| due.saturating_sub(now()))
+                .unwrap_or(IDLE_POLL_SECS)
+                .clamp(1, IDLE_POLL_SECS);
+            tokio::select! {
+                _ = self.wake.notified() => {}
+                _ = tokio::time::sleep(Duration::from_secs(wait)) => {}
+            }
+        }
+    }
+
+    /// Attempts every due job and returns when the next pending one is due.
+    async fn run_due(
+        &self,
+        catalog: &Catalog,
+        storage: &dyn StorageBackend,
+    ) -> io::Result<Option<u64>> {
+        let mut next_due: Option<u64> = None;
+        for job in self.all()? {
+            if job.state != BackupState::Pending {
+                continue;
+            }
+            if job.next_attempt_at > now() {
+                next_due =
+                    Some(next_due.map_or(job.next_attempt_at, |due| due.min(job.next_attempt_at)));
+                continue;
+            }
+            if let Some(job) = self.attempt(job, catalog, storage).await? {
+                if job.state == BackupState::Pending {
+                    next_due = Some(
+                        next_due.map_or(job.next_attempt_at, |due| due.min(job.next_attempt_at)),
+                    );
+                }
+            }
+        }
+        Ok(next_due)
+    }
+
+    async fn attempt(
+        &self,
+        mut job: BackupJob,
+        catalog: &Catalog,
+        storage: &dyn StorageBackend,
+    ) -> io::Result<Option<BackupJob>> {
+        job.state = BackupState::Uploading;
+        job.attempts += 1;
+        job.updated_at = now();
+        if !self.finish(&job)? {
+            return Ok(None);
+        }
+        log::info!(
+            "Backing up {}/{} to {} (attempt {})",
+            job.owner,
+            job.file_id,
+            storage.name(),
+            job.attempts
+        );
+
+        let result = upload(&job, storage).await;
+        job.updated_at = now();
+        match result {
+            Ok(receipt) => {
+                job.state = BackupState::Pinned;
+                job.last_error = None;
+                if !self.finish(&job)? {
+                    return Ok(None);
+                }
+                log::info!(
+                    "📌 Backed up {}/{}, CID {}",
+                    job.owner,
+                    job.file_id,
+                    receipt.cid
+                );
+                if let Some(mut record) = catalog.get(&job.owner, &job.file_id)? {
+                    record.set_receipt(receipt);
+                    catalog.put(&record)?;
+                }
+            }
+            Err(err) => {
+                job.last_error = Some(err.to_string());
+                if job.attempts >= MAX_ATTEMPTS {
+                    job.state = BackupState::Failed;
+                    log::error!(
+                        "Backup of {}/{} failed for good 😭. Error: {}",
+                        job.owner,
+                        job.file_id,
+                        err
+                    );
+                } else {
+                    let delay = (RETRY_BASE_SECS << (job.attempts - 1).min(16)).min(RETRY_MAX_SECS);
+                    job.state = BackupState::Pending;
+                    job.next_attempt_at = now() + delay;
+                    log::warn!(
+                        "Backup of {}/{} failed, retrying in {}s. Error: {}",
+                        job.owner,
+                        job.file_id,
+                        delay,
+                        err
+                    );
+                }
+                if !self.finish(&job)? {
+                    return Ok(None);
+                }
+            }
+        }
+        Ok(Some(job))
+    }
+}
+
+/// Uploads and pins the data file of `job`, then the key envelope and FHE
+/// server key it needs, so a restore brings back a usable dataset.
+async fn upload(job: &BackupJob, storage: &dyn StorageBackend) -> io::Result<BackupReceipt> {
+    let data_path = Path::new(&job.path);
+    let stored = storage.put(data_path).await?;
+    storage.pin(&stored.id).await?;
+    let mut receipt = BackupReceipt::new(
+        storage.name(),
+        &stored.id,
+        &stored.name,
+        stored.size,
+        stored.stored_size,
+    );
+
+    let envelope_path = data_path.with_file_name(ENVELOPE_FILE);
+    if envelope_path.is_file() {
+        receipt.envelope = Some(upload_companion(storage, &envelope_path).await?);
+    }
+    if EncryptionMode::from_file_id(&job.file_id) == EncryptionMode::Fhe {
+        let encoded = BufReader::new(File::open(data_path)?);
+        if let Some(key_id) =
+            FheDataset::peek_key_id(DecoderReader::new(encoded, &BASE64_STANDARD))?
+        {
+            let key_path = fhe_keys::server_key_path(&job.owner, &key_id)?;
+            if key_path.is_file() {
+                receipt.server_key = Some(upload_companion(storage, &key_path).await?);
+                receipt.server_key_id = Some(key_id);
+            } else {
+                log::warn!(
+                    "FHE server key {} of {}/{} is missing, backing up the data alone",
+                    key_id,
+                    job.owner,
+                    job.file_id
+                );
+            }
+        }
+    }
+    Ok(receipt)
+}
+
+async fn upload_companion(
+    storage: &dyn StorageBackend,
+    path: &Path,
+) -> io::Result<CompanionBackup> {
+    let (_, sha256) = hash_file(path)?;
+    let stored = storage.put(path).await?;
+    storage.pin(&stored.id).await?;
+    Ok(CompanionBackup {
+        cid: stored.id,
+        sha256,
+    })
+}
+
+fn to_io(err: sled::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, err)
+}
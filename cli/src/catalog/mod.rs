@@ -0,0 +1,511 @@
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use uuid::Uuid;
+
+/// Ciphertext files produced by `process-data`, in the order we look for them
+/// when recovering a dataset directory.
+const KNOWN_DATA_FILES: [&str; 2] = ["fhe_enc_data.b64", "enc_data.b64"];
+/// Key envelope uploaded next to the data file.
+pub const ENVELOPE_FILE: &str = "enc_sym_keys.b64";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum EncryptionMode {
+    Fhe,
+    Aes,
+}
+
+impl EncryptionMode {
+    /// Datasets uploaded with an `fhe` prefixed identifier hold FHE ciphertexts.
+    pub fn from_file_id(file_id: &str) -> Self {
+        if file_id.starts_with("fhe") {
+            EncryptionMode::Fhe
+        } else {
+            EncryptionMode::Aes
+        }
+    }
+
+    /// Name the uploaded ciphertext is stored under, whatever it was sent as.
+    pub fn data_file(self) -> &'static str {
+        match self {
+            EncryptionMode::Fhe => KNOWN_DATA_FILES[0],
+            EncryptionMode::Aes => KNOWN_DATA_FILES[1],
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DatasetRecord {
+    pub id: String,
+    pub owner: String,
+    pub file_id: String,
+    pub description: String,
+    pub encryption: EncryptionMode,
+    pub data_file: String,
+    pub size: u64,
+    pub sha256: String,
+    /// Modification time of the data file when it was hashed, in seconds.
+    #[serde(default)]
+    pub modified: u64,
+    pub cid: Option<String>,
+    pub stored_at: u64,
+    /// False when the record survived but `store/<owner>/<file_id>` did not.
+    pub present: bool,
+    /// What the storage backend reported for the latest backup.
+    #[serde(default)]
+    pub receipt: Option<BackupReceipt>,
+}
+
+/// Proof of a backup as returned by the storage backend, kept so owners and
+/// buyers can check the CID and size against the backend themselves.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BackupReceipt {
+    pub backend: String,
+    pub cid: String,
+    pub name: String,
+    /// Bytes sent to the backend.
+    pub size: u64,
+    /// Size the backend reported storing, the DAG size on IPFS backends.
+    pub stored_size: u64,
+    pub uploaded_at: u64,
+    /// Backup of the key envelope, for datasets uploaded with one.
+    #[serde(default)]
+    pub envelope: Option<CompanionBackup>,
+    /// Id and backup of the FHE server key the dataset was encrypted for.
+    #[serde(default)]
+    pub server_key_id: Option<String>,
+    #[serde(default)]
+    pub server_key: Option<CompanionBackup>,
+}
+
+/// Backup of a file the dataset cannot be used without, stored separately
+/// from the data file.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CompanionBackup {
+    pub cid: String,
+    pub sha256: String,
+}
+
+impl BackupReceipt {
+    pub fn new(backend: &str, cid: &str, name: &str, size: u64, stored_size: u64) -> Self {
+        BackupReceipt {
+            backend: backend.to_string(),
+            cid: cid.to_string(),
+            name: name.to_string(),
+            size,
+            stored_size,
+            uploaded_at: now(),
+            envelope: None,
+            server_key_id: None,
+            server_key: None,
+        }
+    }
+}
+
+impl DatasetRecord {
+    pub fn new(
+        owner: &str,
+        file_id: &str,
+        description: &str,
+        data_path: &Path,
+    ) -> io::Result<Self> {
+        let (size, sha256) = hash_file(data_path)?;
+        Ok(DatasetRecord {
+            id: dataset_id(owner, file_id),
+            owner: owner.to_string(),
+            file_id: file_id.to_string(),
+            description: description.to_string(),
+            encryption: EncryptionMode::from_file_id(file_id),
+            data_file: data_path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .unwrap_or_default()
+                .to_string(),
+            size,
+            sha256,
+            modified: modified(data_path)?,
+            cid: None,
+            stored_at: now(),
+            present: true,
+            receipt: None,
+        })
+    }
+
+    pub fn set_receipt(&mut self, receipt: BackupReceipt) {
+        self.cid = Some(receipt.cid.clone());
+        self.receipt = Some(receipt);
+    }
+
+    /// Whether the data file at `path` may differ from what was hashed, judged
+    /// by its size and modification time.
+    fn is_stale(&self, path: &Path) -> io::Result<bool> {
+        Ok(fs::metadata(path)?.len() != self.size || modified(path)? != self.modified)
+    }
+}
+
+/// Durable index of every dataset held under `store/`, backed by sled.
+#[derive(Clone)]
+pub struct Catalog {
+    db: sled::Db,
+    datasets: sled::Tree,
+    /// Dataset id to record key, so lookups by id skip the full scan.
+    ids: sled::Tree,
+}
+
+impl Catalog {
+    pub fn open(path: &Path) -> Result<Self, String> {
+        let db = sled::open(path).map_err(|err| format!("Failed to open catalog: {}", err))?;
+        let datasets = db
+            .open_tree("datasets")
+            .map_err(|err| format!("Failed to open catalog: {}", err))?;
+        let ids = db
+            .open_tree("dataset_ids")
+            .map_err(|err| format!("Failed to open catalog: {}", err))?;
+        let catalog = Catalog { db, datasets, ids };
+        // Catalogs from before the index, or a put interrupted between trees
+        if catalog.ids.len() != catalog.datasets.len() {
+            catalog
+                .reindex()
+                .map_err(|err| format!("Failed to index catalog: {}", err))?;
+        }
+        Ok(catalog)
+    }
+
+    fn reindex(&self) -> io::Result<()> {
+        self.ids.clear().map_err(to_io)?;
+        for record in self.all()? {
+            self.ids
+                .insert(
+                    &record.id,
+                    record_key(&record.owner, &record.file_id).as_bytes(),
+                )
+                .map_err(to_io)?;
+        }
+        self.ids.flush().map_err(to_io)?;
+        Ok(())
+    }
+
+    /// Another tree of the catalog database, for node state kept next to the
+    /// dataset records.
+    pub fn tree(&self, name: &str) -> Result<sled::Tree, String> {
+        self.db
+            .open_tree(name)
+            .map_err(|err| format!("Failed to open catalog tree {}: {}", name, err))
+    }
+
+    pub fn put(&self, record: &DatasetRecord) -> io::Result<()> {
+        let value = serde_json::to_vec(record)?;
+        let key = record_key(&record.owner, &record.file_id);
+        self.datasets.insert(&key, value).map_err(to_io)?;
+        self.ids.insert(&record.id, key.as_bytes()).map_err(to_io)?;
+        self.datasets.flush().map_err(to_io)?;
+        self.ids.flush().map_err(to_io)?;
+        Ok(())
+    }
+
+    pub fn get(&self, owner: &str, file_id: &str) -> io::Result<Option<DatasetRecord>> {
+        match self
+            .datasets
+            .get(record_key(owner, file_id))
+            .map_err(to_io)?
+        {
+            Some(value) => Ok(Some(serde_json::from_slice(&value)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Looks a record up by its dataset id rather than by owner and file id.
+    pub fn by_id(&self, id: &str) -> io::Result<Option<DatasetRecord>> {
+        let key = match self.ids.get(id).map_err(to_io)? {
+            Some(key) => key,
+            None => return Ok(None),
+        };
+        match self.datasets.get(key).map_err(to_io)? {
+            Some(value) => {
+                let record: DatasetRecord = serde_json::from_slice(&value)?;
+                Ok((record.id == id).then_some(record))
+            }
+            None => Ok(None),
+        }
+    }
+
+    pub fn by_owner(&self, owner: &str) -> io::Result<Vec<DatasetRecord>> {
+        self.scan(self.datasets.scan_prefix(format!("{}/", owner)))
+    }
+
+    pub fn all(&self) -> io::Result<Vec<DatasetRecord>> {
+        self.scan(self.datasets.iter())
+    }
+
+    /// Records grouped by owner, the shape `/alldata` has always returned.
+    pub fn grouped(&self) -> io::Result<BTreeMap<String, Vec<DatasetRecord>>> {
+        let mut grouped: BTreeMap<String, Vec<DatasetRecord>> = BTreeMap::new();
+        for record in self.all()? {
+            grouped
+                .entry(record.owner.clone())
+                .or_default()
+                .push(record);
+        }
+        Ok(grouped)
+    }
+
+    fn scan(&self, iter: sled::Iter) -> io::Result<Vec<DatasetRecord>> {
+        let mut records = Vec::new();
+        for item in iter {
+            let (_, value) = item.map_err(to_io)?;
+            records.push(serde_json::from_slice(&value)?);
+        }
+        Ok(records)
+    }
+
+    /// Brings the catalog in line with what is actually on disk: datasets found
+    /// under `store_root` without a record are added, files whose size or
+    /// modification time changed are re-hashed and records whose directory
+    /// vanished are flagged as missing (their CID is still needed to restore
+    /// them). Returns the datasets whose content is new, with their data file,
+    /// as their backups no longer match it.
+    pub fn reconcile(&self, store_root: &Path) -> io::Result<Vec<(DatasetRecord, PathBuf)>> {
+        let mut seen = Vec::new();
+        let mut changed = Vec::new();
+        for owner_entry in fs::read_dir(store_root)? {
+            let owner_entry = owner_entry?;
+            if !owner_entry.file_type()?.is_dir() {
+                continue;
+            }
+            let owner = owner_entry.file_name().to_string_lossy().to_string();
+            for dataset_entry in fs::read_dir(owner_entry.path())? {
+                let dataset_entry = dataset_entry?;
+                if !dataset_entry.file_type()?.is_dir() {
+                    continue;
+                }
+                let file_id = dataset_entry.file_name().to_string_lossy().to_string();
+                let data_path = match find_data_file(&dataset_entry.path())? {
+                    Some(path) => path,
+                    None => {
+                        log::warn!("Skipping {}/{}: no data file found", owner, file_id);
+                        continue;
+                    }
+                };
+                seen.push(record_key(&owner, &file_id));
+
+                match self.get(&owner, &file_id)? {
+                    Some(mut record) => {
+                        if !record.is_stale(&data_path)? {
+                            if !record.present {
+                                record.present = true;
+                                self.put(&record)?;
+                            }
+                            continue;
+                        }
+                        let (size, sha256) = hash_file(&data_path)?;
+                        record.size = size;
+                        record.modified = modified(&data_path)?;
+                        record.present = true;
+                        if record.sha256 != sha256 {
+                            log::warn!("Dataset {}/{} changed on disk", owner, file_id);
+                            // The old backup holds the old content, restoring
+                            // it would fail the hash check
+                            record.sha256 = sha256;
+                            record.cid = None;
+                            record.receipt = None;
+                            changed.push((record.clone(), data_path));
+                        }
+                        self.put(&record)?;
+                    }
+                    None => {
+                        log::info!("Recovered dataset {}/{} into the catalog", owner, file_id);
+                        let record = DatasetRecord::new(&owner, &file_id, "", &data_path)?;
+                        self.put(&record)?;
+                        changed.push((record, data_path));
+                    }
+                }
+            }
+        }
+
+        for mut record in self.all()? {
+            if record.present && !seen.contains(&record_key(&record.owner, &record.file_id)) {
+                log::warn!(
+                    "Dataset {}/{} is missing from the store directory",
+                    record.owner,
+                    record.file_id
+                );
+                record.present = false;
+                self.put(&record)?;
+            }
+        }
+        Ok(changed)
+    }
+}
+
+/// Errors unless `id` is safe as one path component under `store/`: ASCII
+/// letters, digits, `_` and `-` only.
+pub fn check_id(kind: &str, id: &str) -> io::Result<()> {
+    if !id.is_empty()
+        && id
+            .bytes()
+            .all(|byte| byte.is_ascii_alphanumeric() || byte == b'_' || byte == b'-')
+    {
+        Ok(())
+    } else {
+        Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Invalid {} {:?}, use letters, digits, _ and -", kind, id),
+        ))
+    }
+}
+
+pub fn dataset_id(owner: &str, file_id: &str) -> String {
+    Uuid::new_v5(&Uuid::NAMESPACE_OID, record_key(owner, file_id).as_bytes()).to_string()
+}
+
+pub(crate) fn record_key(owner: &str, file_id: &str) -> String {
+    format!("{}/{}", owner, file_id)
+}
+
+/// The data file of a dataset directory. Uploads from before data files had
+/// fixed names kept the name they were sent with, so any other file will do,
+/// but never the key envelope or a partial download (dot files).
+fn find_data_file(dir: &Path) -> io::Result<Option<PathBuf>> {
+    for name in KNOWN_DATA_FILES {
+        let path = dir.join(name);
+        if path.is_file() {
+            return Ok(Some(path));
+        }
+    }
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        let name = name.to_string_lossy();
+        if entry.file_type()?.is_file() && name != ENVELOPE_FILE && !name.starts_with('.') {
+            return Ok(Some(entry.path()));
+        }
+    }
+    Ok(None)
+}
+
+/// Size and hex encoded SHA-256 of a file, read in a streaming fashion.
+pub fn hash_file(path: &Path) -> io::Result<(u64, String)> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::new();
+    let size = io::copy(&mut file, &mut hasher)?;
+    Ok((size, format!("{:x}", hasher.finalize())))
+}
+
+/// Modification time of a file in seconds since the epoch.
+fn modified(path: &Path) -> io::Result<u64> {
+    Ok(fs::metadata(path)?
+        .modified()?
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default())
+}
+
+pub(crate) fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+fn to_io(err: sled::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, err)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OWNER: &str = "0xf39fd6e51aad88f6f4ce6ab8827279cfffb92266";
+
+    fn setup() -> (tempfile::TempDir, Catalog, PathBuf) {
+        let dir = tempfile::tempdir().unwrap();
+        let catalog = Catalog::open(&dir.path().join("catalog")).unwrap();
+        let store = dir.path().join("store");
+        fs::create_dir_all(&store).unwrap();
+        (dir, catalog, store)
+    }
+
+    fn write(store: &Path, file_id: &str, name: &str, contents: &[u8]) -> PathBuf {
+        let dataset = store.join(OWNER).join(file_id);
+        fs::create_dir_all(&dataset).unwrap();
+        let path = dataset.join(name);
+        fs::write(&path, contents).unwrap();
+        path
+    }
+
+    #[test]
+    fn reconcile_recovers_unknown_datasets() {
+        let (_dir, catalog, store) = setup();
+        let path = write(&store, "data", "enc_data.b64", b"ciphertext");
+        write(&store, "data", ENVELOPE_FILE, b"{}");
+
+        let changed = catalog.reconcile(&store).unwrap();
+        assert_eq!(changed.len(), 1);
+        assert_eq!(changed[0].1, path);
+        let record = catalog.get(OWNER, "data").unwrap().unwrap();
+        assert_eq!(record.data_file, "enc_data.b64");
+        assert_eq!(record.sha256, hash_file(&path).unwrap().1);
+        assert!(record.present);
+
+        // Nothing changed since, nothing to back up again
+        assert!(catalog.reconcile(&store).unwrap().is_empty());
+    }
+
+    #[test]
+    fn reconcile_drops_the_backup_of_changed_datasets() {
+        let (_dir, catalog, store) = setup();
+        let path = write(&store, "data", "enc_data.b64", b"ciphertext");
+        catalog.reconcile(&store).unwrap();
+        let mut record = catalog.get(OWNER, "data").unwrap().unwrap();
+        record.set_receipt(BackupReceipt::new("local", "cid", "enc_data.b64", 10, 10));
+        catalog.put(&record).unwrap();
+
+        fs::write(&path, b"other, longer ciphertext").unwrap();
+        let changed = catalog.reconcile(&store).unwrap();
+        assert_eq!(changed.len(), 1);
+        let record = catalog.get(OWNER, "data").unwrap().unwrap();
+        assert_eq!(record.cid, None);
+        assert!(record.receipt.is_none());
+        assert_eq!(record.size, 24);
+        assert_eq!(record.sha256, hash_file(&path).unwrap().1);
+    }
+
+    #[test]
+    fn reconcile_flags_missing_datasets() {
+        let (_dir, catalog, store) = setup();
+        write(&store, "data", "enc_data.b64", b"ciphertext");
+        catalog.reconcile(&store).unwrap();
+
+        fs::remove_dir_all(store.join(OWNER).join("data")).unwrap();
+        assert!(catalog.reconcile(&store).unwrap().is_empty());
+        assert!(!catalog.get(OWNER, "data").unwrap().unwrap().present);
+    }
+
+    #[test]
+    fn reconcile_never_takes_the_envelope_for_data() {
+        let (_dir, catalog, store) = setup();
+        write(&store, "keys_only", ENVELOPE_FILE, b"{}");
+        write(&store, "keys_only", ".tmpAbC123", b"partial");
+        let legacy = write(&store, "legacy", "upload.b64", b"ciphertext");
+        write(&store, "legacy", ENVELOPE_FILE, b"{}");
+
+        let changed = catalog.reconcile(&store).unwrap();
+        assert_eq!(changed.len(), 1);
+        assert_eq!(changed[0].1, legacy);
+        assert!(catalog.get(OWNER, "keys_only").unwrap().is_none());
+    }
+
+    #[test]
+    fn by_id_finds_records() {
+        let (_dir, catalog, store) = setup();
+        write(&store, "data", "enc_data.b64", b"ciphertext");
+        catalog.reconcile(&store).unwrap();
+        let id = dataset_id(OWNER, "data");
+        assert_eq!(catalog.by_id(&id).unwrap().unwrap().file_id, "data");
+        assert!(catalog.by_id("unknown").unwrap().is_none());
+    }
+}
@@ -0,0 +1,579 @@
+use crate::fhe_keys;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::io::{self, Cursor, Read};
+use tfhe::integer::{IntegerCiphertext, RadixCiphertext, ServerKey, SignedRadixCiphertext};
+
+/// Leading bytes of every versioned container; files without them predate the
+/// format and are read through the legacy path.
+const MAGIC: &[u8; 4] = b"DZFH";
+/// Bumped whenever the header or payload layout changes.
+///
+/// 1. Server key embedded in the payload.
+/// 2. Server key stored separately and referenced by `key_id`.
+/// 3. Per column encoding: signedness and fixed-point scale.
+/// 4. Per column declared maximum.
+pub const FORMAT_VERSION: u16 = 4;
+/// Parameters and width used by uploads made before the header existed.
+const LEGACY_PARAM_SET: &str = "PARAM_MESSAGE_2_CARRY_3_KS_PBS";
+const LEGACY_NUM_BLOCKS: usize = 8;
+
+/// How the values of a column were turned into integers before encryption.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Encoding {
+    /// Values are two's complement and read as `SignedRadixCiphertext`.
+    pub signed: bool,
+    /// Values were multiplied by `10^scale` to keep that many decimals.
+    pub scale: u32,
+}
+
+/// One CSV column, encrypted value by value.
+#[derive(Serialize, Deserialize)]
+pub struct EncryptedColumn {
+    pub name: String,
+    pub values: Vec<RadixCiphertext>,
+    /// Kept in the header, not the payload.
+    #[serde(skip)]
+    pub encoding: Encoding,
+    /// Largest absolute encoded value, as declared by the owner; anything the
+    /// width holds when `None`. Kept in the header.
+    #[serde(skip)]
+    pub max: Option<u64>,
+}
+
+impl EncryptedColumn {
+    /// The values of a signed column as the signed ciphertexts they are.
+    pub fn signed_values(&self) -> Vec<SignedRadixCiphertext> {
+        self.values
+            .iter()
+            .map(|value| SignedRadixCiphertext::from_blocks(value.blocks().to_vec()))
+            .collect()
+    }
+}
+
+/// Named ciphertexts a computation returns together, such as the partial
+/// sums of a variance, for the client decrypt server to decrypt as one.
+#[derive(Serialize, Deserialize)]
+pub struct EncryptedFields {
+    pub fields: Vec<(String, RadixCiphertext)>,
+    /// The fields hold signed ciphertexts.
+    pub signed: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ColumnInfo {
+    pub name: String,
+    pub rows: u64,
+    pub encoding: Encoding,
+    pub max: Option<u64>,
+}
+
+/// Column of version 1 and 2 headers, all of them unsigned integers.
+#[derive(Deserialize)]
+struct ColumnInfoV2 {
+    name: String,
+    rows: u64,
+}
+
+impl From<ColumnInfoV2> for ColumnInfo {
+    fn from(column: ColumnInfoV2) -> Self {
+        ColumnInfo {
+            name: column.name,
+            rows: column.rows,
+            encoding: Encoding::default(),
+            max: None,
+        }
+    }
+}
+
+/// Column of version 3 headers, from before maximums were declared.
+#[derive(Deserialize)]
+struct ColumnInfoV3 {
+    name: String,
+    rows: u64,
+    encoding: Encoding,
+}
+
+impl From<ColumnInfoV3> for ColumnInfo {
+    fn from(column: ColumnInfoV3) -> Self {
+        ColumnInfo {
+            name: column.name,
+            rows: column.rows,
+            encoding: column.encoding,
+            max: None,
+        }
+    }
+}
+
+/// Self-describing header written in front of the payload, so readers can
+/// validate a dataset before touching the ciphertexts.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ContainerHeader {
+    pub param_set: String,
+    pub num_blocks: u32,
+    /// Server key the ciphertexts were produced for, stored apart from the data.
+    pub key_id: String,
+    pub row_count: u64,
+    pub columns: Vec<ColumnInfo>,
+    pub payload_len: u64,
+    /// SHA-256 of the payload bytes.
+    pub checksum: [u8; 32],
+}
+
+/// Header of version 1 containers, which carried their server key inline.
+#[derive(Deserialize)]
+struct ContainerHeaderV1 {
+    param_set: String,
+    num_blocks: u32,
+    row_count: u64,
+    columns: Vec<ColumnInfoV2>,
+    payload_len: u64,
+    checksum: [u8; 32],
+}
+
+/// Header of version 2 and 3 containers, the same as the current one but for
+/// their columns, `C`.
+#[derive(Deserialize)]
+struct ContainerHeaderV2<C> {
+    param_set: String,
+    num_blocks: u32,
+    key_id: String,
+    row_count: u64,
+    columns: Vec<C>,
+    payload_len: u64,
+    checksum: [u8; 32],
+}
+
+impl<C: Into<ColumnInfo>> ContainerHeaderV2<C> {
+    fn upgrade(self) -> ContainerHeader {
+        ContainerHeader {
+            param_set: self.param_set,
+            num_blocks: self.num_blocks,
+            key_id: self.key_id,
+            row_count: self.row_count,
+            columns: self.columns.into_iter().map(Into::into).collect(),
+            payload_len: self.payload_len,
+            checksum: self.checksum,
+        }
+    }
+}
+
+/// Everything `process-data` writes to `fhe_enc_data.b64`: one ciphertext
+/// vector per encrypted column and the id of the server key to evaluate them
+/// with. Datasets from before keys were split out still carry `server_key`.
+///
+/// Layout: `MAGIC | version: u16 LE | header_len: u32 LE | header | payload`
+/// where header and payload are bincode encoded.
+pub struct FheDataset {
+    pub param_set: String,
+    pub num_blocks: usize,
+    pub key_id: Option<String>,
+    pub server_key: Option<ServerKey>,
+    pub columns: Vec<EncryptedColumn>,
+}
+
+impl FheDataset {
+    pub fn to_bytes(&self) -> io::Result<Vec<u8>> {
+        let key_id = self
+            .key_id
+            .clone()
+            .ok_or_else(|| invalid("Datasets are written with a server key id"))?;
+        let payload = bincode::serialize(&self.columns).map_err(invalid)?;
+        let columns: Vec<ColumnInfo> = self
+            .columns
+            .iter()
+            .map(|column| ColumnInfo {
+                name: column.name.clone(),
+                rows: column.values.len() as u64,
+                encoding: column.encoding,
+                max: column.max,
+            })
+            .collect();
+        let header = ContainerHeader {
+            param_set: self.param_set.clone(),
+            num_blocks: self.num_blocks as u32,
+            key_id,
+            row_count: columns.iter().map(|column| column.rows).max().unwrap_or(0),
+            columns,
+            payload_len: payload.len() as u64,
+            checksum: Sha256::digest(&payload).into(),
+        };
+        let header = bincode::serialize(&header).map_err(invalid)?;
+
+        let mut bytes = Vec::with_capacity(MAGIC.len() + 6 + header.len() + payload.len());
+        bytes.extend_from_slice(MAGIC);
+        bytes.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+        bytes.extend_from_slice(&(header.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(&header);
+        bytes.extend_from_slice(&payload);
+        Ok(bytes)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> io::Result<Self> {
+        if !bytes.starts_with(MAGIC) {
+            return Self::from_unversioned_bytes(bytes);
+        }
+        let (version, header, payload) = read_header(bytes)?;
+        if payload.len() as u64 != header.payload_len {
+            return Err(invalid(format!(
+                "Truncated dataset: expected {} payload bytes, found {}",
+                header.payload_len,
+                payload.len()
+            )));
+        }
+        let checksum: [u8; 32] = Sha256::digest(payload).into();
+        if checksum != header.checksum {
+            return Err(invalid("Dataset checksum mismatch"));
+        }
+
+        let (server_key, mut columns) = if version == 1 {
+            let (server_key, columns): (ServerKey, Vec<EncryptedColumn>) =
+                bincode::deserialize(payload).map_err(invalid)?;
+            (Some(server_key), columns)
+        } else {
+            (None, bincode::deserialize(payload).map_err(invalid)?)
+        };
+        for (column, info) in columns.iter_mut().zip(&header.columns) {
+            column.encoding = info.encoding;
+            column.max = info.max;
+        }
+        let dataset = FheDataset {
+            param_set: header.param_set.clone(),
+            num_blocks: header.num_blocks as usize,
+            key_id: (!header.key_id.is_empty()).then(|| header.key_id.clone()),
+            server_key,
+            columns,
+        };
+        dataset.validate(&header)?;
+        Ok(dataset)
+    }
+
+    /// Checks the decoded payload against what its header promised.
+    fn validate(&self, header: &ContainerHeader) -> io::Result<()> {
+        if self.columns.len() != header.columns.len() {
+            return Err(invalid("Column count does not match header"));
+        }
+        for (column, info) in self.columns.iter().zip(&header.columns) {
+            if column.name != info.name || column.values.len() as u64 != info.rows {
+                return Err(invalid(format!(
+                    "Column {} does not match header",
+                    info.name
+                )));
+            }
+            if column
+                .values
+                .iter()
+                .any(|value| value.blocks().len() != self.num_blocks)
+            {
+                return Err(invalid(format!(
+                    "Column {} holds ciphertexts with an unexpected number of blocks",
+                    info.name
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Uploads made before the header existed: either the named columns
+    /// without a header, or a single unnamed column stored as a server key
+    /// followed by ciphertexts until the end of the buffer.
+    fn from_unversioned_bytes(bytes: &[u8]) -> io::Result<Self> {
+        let (server_key, columns) = match bincode::deserialize(bytes) {
+            Ok(dataset) => dataset,
+            Err(_) => {
+                let mut serialized_data = Cursor::new(bytes);
+                let server_key: ServerKey =
+                    bincode::deserialize_from(&mut serialized_data).map_err(invalid)?;
+                let mut values = Vec::new();
+                while (serialized_data.position() as usize) < bytes.len() {
+                    values.push(bincode::deserialize_from(&mut serialized_data).map_err(invalid)?);
+                }
+                let columns = vec![EncryptedColumn {
+                    name: "value".to_string(),
+                    values,
+                    encoding: Encoding::default(),
+                    max: None,
+                }];
+                (server_key, columns)
+            }
+        };
+        log::warn!("Reading an unversioned FHE dataset, re-run process-data to upgrade it");
+        Ok(FheDataset {
+            param_set: LEGACY_PARAM_SET.to_string(),
+            num_blocks: LEGACY_NUM_BLOCKS,
+            key_id: None,
+            server_key: Some(server_key),
+            columns,
+        })
+    }
+
+    /// Server key id referenced by an encoded dataset, read from its header
+    /// alone so the payload never has to be loaded. `None` for datasets that
+    /// embed their server key.
+    pub fn peek_key_id<R: Read>(reader: R) -> io::Result<Option<String>> {
+        let mut prelude = Vec::with_capacity(MAGIC.len() + 6);
+        let mut reader = reader.take(MAGIC.len() as u64 + 6);
+        reader.read_to_end(&mut prelude)?;
+        if prelude.len() < MAGIC.len() + 6 || !prelude.starts_with(MAGIC) {
+            return Ok(None);
+        }
+        let header_len = u32::from_le_bytes([prelude[6], prelude[7], prelude[8], prelude[9]]);
+        let mut bytes = prelude;
+        reader.set_limit(header_len as u64);
+        reader.read_to_end(&mut bytes)?;
+        let (_, header, _) = read_header(&bytes)?;
+        Ok((!header.key_id.is_empty()).then_some(header.key_id))
+    }
+
+    /// The requested column, or the first one when none is named.
+    pub fn column(&self, name: Option<&str>) -> Option<&EncryptedColumn> {
+        match name {
+            Some(name) => self.columns.iter().find(|column| column.name == name),
+            None => self.columns.first(),
+        }
+    }
+
+    pub fn column_names(&self) -> Vec<&str> {
+        self.columns
+            .iter()
+            .map(|column| column.name.as_str())
+            .collect()
+    }
+}
+
+/// Parses the fixed prelude and header of a versioned container, returning
+/// the format version, the header and the remaining payload bytes.
+fn read_header(bytes: &[u8]) -> io::Result<(u16, ContainerHeader, &[u8])> {
+    let prelude = MAGIC.len() + 6;
+    if bytes.len() < prelude || !bytes.starts_with(MAGIC) {
+        return Err(invalid("Not a Datazen FHE container"));
+    }
+    let version = u16::from_le_bytes([bytes[4], bytes[5]]);
+    if version == 0 || version > FORMAT_VERSION {
+        return Err(invalid(format!(
+            "Unsupported container version {}, this node reads up to version {}",
+            version, FORMAT_VERSION
+        )));
+    }
+    let header_len = u32::from_le_bytes([bytes[6], bytes[7], bytes[8], bytes[9]]) as usize;
+    if bytes.len() < prelude + header_len {
+        return Err(invalid("Truncated dataset header"));
+    }
+    let header_bytes = &bytes[prelude..prelude + header_len];
+    let header = if version == 1 {
+        let header: ContainerHeaderV1 = bincode::deserialize(header_bytes).map_err(invalid)?;
+        ContainerHeader {
+            param_set: header.param_set,
+            num_blocks: header.num_blocks,
+            key_id: String::new(),
+            row_count: header.row_count,
+            columns: header.columns.into_iter().map(ColumnInfo::from).collect(),
+            payload_len: header.payload_len,
+            checksum: header.checksum,
+        }
+    } else if version == 2 {
+        let header: ContainerHeaderV2<ColumnInfoV2> =
+            bincode::deserialize(header_bytes).map_err(invalid)?;
+        header.upgrade()
+    } else if version == 3 {
+        let header: ContainerHeaderV2<ColumnInfoV3> =
+            bincode::deserialize(header_bytes).map_err(invalid)?;
+        header.upgrade()
+    } else {
+        bincode::deserialize(header_bytes).map_err(invalid)?
+    };
+    // Version 1 headers predate key ids
+    if !header.key_id.is_empty() {
+        fhe_keys::check_key_id(&header.key_id)?;
+    }
+    Ok((version, header, &bytes[prelude + header_len..]))
+}
+
+fn invalid<E>(err: E) -> io::Error
+where
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    io::Error::new(io::ErrorKind::InvalidData, err)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY_ID: &str = "db6fc47ca806feeb7b5a9e364df739ab";
+
+    /// A dataset without ciphertexts, which the container treats like any other.
+    fn dataset() -> FheDataset {
+        FheDataset {
+            param_set: LEGACY_PARAM_SET.to_string(),
+            num_blocks: 16,
+            key_id: Some(KEY_ID.to_string()),
+            server_key: None,
+            columns: vec![EncryptedColumn {
+                name: "temp".to_string(),
+                values: Vec::new(),
+                encoding: Encoding {
+                    signed: true,
+                    scale: 2,
+                },
+                max: Some(1000),
+            }],
+        }
+    }
+
+    /// A container of an older `version`, its header written field by field
+    /// the way bincode lays out the structs of that version.
+    fn container<H: Serialize>(version: u16, header: &H, payload: &[u8]) -> Vec<u8> {
+        let header = bincode::serialize(header).unwrap();
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend_from_slice(&version.to_le_bytes());
+        bytes.extend_from_slice(&(header.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(&header);
+        bytes.extend_from_slice(payload);
+        bytes
+    }
+
+    fn payload() -> (Vec<u8>, [u8; 32]) {
+        let payload = bincode::serialize(&dataset().columns).unwrap();
+        let checksum = Sha256::digest(&payload).into();
+        (payload, checksum)
+    }
+
+    fn error(bytes: &[u8]) -> String {
+        match FheDataset::from_bytes(bytes) {
+            Ok(_) => panic!("container was accepted"),
+            Err(err) => err.to_string(),
+        }
+    }
+
+    #[test]
+    fn round_trips_the_header() {
+        let dataset = FheDataset::from_bytes(&dataset().to_bytes().unwrap()).unwrap();
+        assert_eq!(dataset.num_blocks, 16);
+        assert_eq!(dataset.key_id.as_deref(), Some(KEY_ID));
+        let column = dataset.column(None).unwrap();
+        assert_eq!(column.name, "temp");
+        assert_eq!(
+            column.encoding,
+            Encoding {
+                signed: true,
+                scale: 2
+            }
+        );
+        assert_eq!(column.max, Some(1000));
+    }
+
+    #[test]
+    fn rejects_a_checksum_mismatch() {
+        let mut bytes = dataset().to_bytes().unwrap();
+        *bytes.last_mut().unwrap() ^= 1;
+        assert!(error(&bytes).contains("checksum mismatch"));
+    }
+
+    #[test]
+    fn rejects_a_truncated_payload() {
+        let bytes = dataset().to_bytes().unwrap();
+        assert!(error(&bytes[..bytes.len() - 1]).contains("Truncated dataset"));
+    }
+
+    #[test]
+    fn rejects_a_truncated_header() {
+        let bytes = dataset().to_bytes().unwrap();
+        assert!(error(&bytes[..MAGIC.len() + 8]).contains("Truncated dataset header"));
+    }
+
+    #[test]
+    fn rejects_newer_versions() {
+        let mut bytes = dataset().to_bytes().unwrap();
+        bytes[4..6].copy_from_slice(&(FORMAT_VERSION + 1).to_le_bytes());
+        assert!(error(&bytes).contains("Unsupported container version"));
+    }
+
+    #[test]
+    fn upgrades_version_1_headers() {
+        let (payload, checksum) = payload();
+        let header = (
+            LEGACY_PARAM_SET,
+            16u32,
+            0u64,
+            vec![("temp", 0u64)],
+            payload.len() as u64,
+            checksum,
+        );
+        let bytes = container(1, &header, &payload);
+        let (version, header, rest) = read_header(&bytes).unwrap();
+        assert_eq!(version, 1);
+        assert_eq!(header.key_id, "");
+        assert_eq!(header.columns[0].name, "temp");
+        assert_eq!(header.columns[0].encoding, Encoding::default());
+        assert_eq!(header.columns[0].max, None);
+        assert_eq!(rest, &payload[..]);
+    }
+
+    #[test]
+    fn upgrades_version_2_containers() {
+        let (payload, checksum) = payload();
+        let header = (
+            LEGACY_PARAM_SET,
+            16u32,
+            KEY_ID,
+            0u64,
+            vec![("temp", 0u64)],
+            payload.len() as u64,
+            checksum,
+        );
+        let dataset = FheDataset::from_bytes(&container(2, &header, &payload)).unwrap();
+        assert_eq!(dataset.key_id.as_deref(), Some(KEY_ID));
+        let column = dataset.column(Some("temp")).unwrap();
+        assert_eq!(column.encoding, Encoding::default());
+        assert_eq!(column.max, None);
+    }
+
+    #[test]
+    fn upgrades_version_3_containers() {
+        let (payload, checksum) = payload();
+        let encoding = Encoding {
+            signed: true,
+            scale: 2,
+        };
+        let header = (
+            LEGACY_PARAM_SET,
+            16u32,
+            KEY_ID,
+            0u64,
+            vec![("temp", 0u64, encoding)],
+            payload.len() as u64,
+            checksum,
+        );
+        let dataset = FheDataset::from_bytes(&container(3, &header, &payload)).unwrap();
+        let column = dataset.column(Some("temp")).unwrap();
+        assert_eq!(column.encoding, encoding);
+        assert_eq!(column.max, None);
+    }
+
+    #[test]
+    fn peeks_the_key_id() {
+        let bytes = dataset().to_bytes().unwrap();
+        assert_eq!(
+            FheDataset::peek_key_id(&bytes[..]).unwrap().as_deref(),
+            Some(KEY_ID)
+        );
+        assert_eq!(FheDataset::peek_key_id(&b"legacy"[..]).unwrap(), None);
+    }
+
+    #[test]
+    fn rejects_key_ids_that_are_not_hashes() {
+        for key_id in [
+            "../../store/0xabc/data/enc_sym_keys",
+            "DB6FC47CA806FEEB7B5A9E364DF739AB",
+            "db6fc47ca806feeb7b5a9e364df739a",
+            "db6fc47ca806feeb7b5a9e364df739ab/",
+        ] {
+            let mut dataset = dataset();
+            dataset.key_id = Some(key_id.to_string());
+            let bytes = dataset.to_bytes().unwrap();
+            assert!(error(&bytes).contains("Invalid FHE key id"), "{}", key_id);
+            assert!(FheDataset::peek_key_id(&bytes[..]).is_err(), "{}", key_id);
+        }
+    }
+}
@@ -0,0 +1,407 @@
+use aes_gcm::aead::{Aead, KeyInit, Payload};
+use aes_gcm::{Aes256Gcm, Nonce};
+use base64::engine::general_purpose::STANDARD as BASE64_STANDARD;
+use base64::Engine;
+use hkdf::Hkdf;
+use rand::rngs::OsRng;
+use rand::Rng;
+use rsa::pkcs8::der::pem::{self, LineEnding};
+use rsa::pkcs8::{DecodePrivateKey, DecodePublicKey, EncodePrivateKey, EncodePublicKey};
+use rsa::{Oaep, Pkcs1v15Encrypt, RsaPrivateKey, RsaPublicKey};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use umbral_pre::{Capsule, DefaultDeserialize, DefaultSerialize};
+use x25519_dalek::{EphemeralSecret, PublicKey as X25519PublicKey, StaticSecret};
+
+/// Bumped whenever the envelope layout changes.
+const ENVELOPE_VERSION: u8 = 1;
+/// Only unwrapped for keys uploaded before envelopes existed; new uploads
+/// carrying it are refused, so the node is no padding oracle.
+const RSA_PKCS1V15: &str = "rsa-pkcs1v15";
+const RSA_OAEP_SHA256: &str = "rsa-oaep-sha256";
+const X25519_HKDF_AES256GCM: &str = "x25519-hkdf-sha256-aes256gcm";
+const X25519_HKDF_INFO: &[u8] = b"datazen key wrap v1";
+/// Umbral capsule and DEM ciphertext, re-encryptable for buyers by the node.
+pub const UMBRAL_PRE: &str = "umbral-pre-secp256k1";
+
+/// DER prefixes of X25519 SubjectPublicKeyInfo and PKCS#8 documents (RFC 8410),
+/// followed by the 32 raw key bytes.
+const X25519_SPKI_PREFIX: [u8; 12] = [
+    0x30, 0x2a, 0x30, 0x05, 0x06, 0x03, 0x2b, 0x65, 0x6e, 0x03, 0x21, 0x00,
+];
+const X25519_PKCS8_PREFIX: [u8; 16] = [
+    0x30, 0x2e, 0x02, 0x01, 0x00, 0x30, 0x05, 0x06, 0x03, 0x2b, 0x65, 0x6e, 0x04, 0x22, 0x04, 0x20,
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    Owner,
+    Node,
+    Auditor,
+}
+
+pub enum PublicKey {
+    Rsa(RsaPublicKey),
+    X25519(X25519PublicKey),
+    Secp256k1(umbral_pre::PublicKey),
+}
+
+pub enum PrivateKey {
+    Rsa(Box<RsaPrivateKey>),
+    X25519(StaticSecret),
+    Secp256k1(umbral_pre::SecretKey),
+}
+
+impl PublicKey {
+    /// Parses an RSA, X25519 or secp256k1 `PUBLIC KEY` PEM as written by `key-gen`.
+    pub fn from_pem(pem_str: &str) -> Result<Self, String> {
+        let (_, der) =
+            pem::decode_vec(pem_str.as_bytes()).map_err(|err| format!("Invalid PEM: {}", err))?;
+        if der.len() == X25519_SPKI_PREFIX.len() + 32 && der.starts_with(&X25519_SPKI_PREFIX) {
+            let raw: [u8; 32] = der[X25519_SPKI_PREFIX.len()..].try_into().unwrap();
+            return Ok(PublicKey::X25519(X25519PublicKey::from(raw)));
+        }
+        if let Ok(key) = k256::PublicKey::from_public_key_der(&der) {
+            return umbral_pre::PublicKey::try_from_compressed_bytes(&key.to_sec1_bytes())
+                .map(PublicKey::Secp256k1);
+        }
+        RsaPublicKey::from_public_key_der(&der)
+            .map(PublicKey::Rsa)
+            .map_err(|err| format!("Unsupported public key: {}", err))
+    }
+
+    pub fn to_der(&self) -> Result<Vec<u8>, String> {
+        match self {
+            PublicKey::Rsa(key) => key
+                .to_public_key_der()
+                .map(|der| der.as_bytes().to_vec())
+                .map_err(|err| format!("Failed to encode public key: {}", err)),
+            PublicKey::X25519(key) => Ok([&X25519_SPKI_PREFIX[..], key.as_bytes()].concat()),
+            PublicKey::Secp256k1(key) => {
+                k256::PublicKey::from_sec1_bytes(&key.to_compressed_bytes())
+                    .map_err(|err| format!("Invalid secp256k1 key: {}", err))?
+                    .to_public_key_der()
+                    .map(|der| der.as_bytes().to_vec())
+                    .map_err(|err| format!("Failed to encode public key: {}", err))
+            }
+        }
+    }
+
+    pub fn to_pem(&self) -> Result<String, String> {
+        pem::encode_string("PUBLIC KEY", LineEnding::default(), &self.to_der()?)
+            .map_err(|err| format!("Failed to encode public key: {}", err))
+    }
+
+    /// Hex SHA-256 of the DER encoded public key.
+    pub fn fingerprint(&self) -> Result<String, String> {
+        Ok(format!("{:x}", Sha256::digest(self.to_der()?)))
+    }
+}
+
+impl PrivateKey {
+    /// Parses an RSA, X25519 or secp256k1 PKCS#8 `PRIVATE KEY` PEM as written
+    /// by `key-gen`.
+    pub fn from_pem(pem_str: &str) -> Result<Self, String> {
+        let (_, der) =
+            pem::decode_vec(pem_str.as_bytes()).map_err(|err| format!("Invalid PEM: {}", err))?;
+        if der.len() == X25519_PKCS8_PREFIX.len() + 32 && der.starts_with(&X25519_PKCS8_PREFIX) {
+            let raw: [u8; 32] = der[X25519_PKCS8_PREFIX.len()..].try_into().unwrap();
+            return Ok(PrivateKey::X25519(StaticSecret::from(raw)));
+        }
+        if let Ok(key) = k256::SecretKey::from_pkcs8_der(&der) {
+            // umbral-pre only hands out the SecretBox it parses keys from, so
+            // overwrite the scalar of a throwaway key
+            let mut scalar = umbral_pre::SecretKey::random().to_be_bytes();
+            scalar.as_mut_secret().copy_from_slice(&key.to_bytes());
+            return umbral_pre::SecretKey::try_from_be_bytes(&scalar)
+                .map(PrivateKey::Secp256k1)
+                .map_err(|err| format!("Invalid secp256k1 key: {}", err));
+        }
+        RsaPrivateKey::from_pkcs8_der(&der)
+            .map(|key| PrivateKey::Rsa(Box::new(key)))
+            .map_err(|err| format!("Unsupported private key: {}", err))
+    }
+
+    pub fn generate_x25519() -> Self {
+        PrivateKey::X25519(StaticSecret::random_from_rng(OsRng))
+    }
+
+    pub fn generate_secp256k1() -> Self {
+        PrivateKey::Secp256k1(umbral_pre::SecretKey::random())
+    }
+
+    pub fn to_pem(&self) -> Result<String, String> {
+        match self {
+            PrivateKey::Rsa(key) => key
+                .to_pkcs8_pem(LineEnding::default())
+                .map(|pem| pem.to_string())
+                .map_err(|err| format!("Failed to encode private key: {}", err)),
+            PrivateKey::X25519(secret) => {
+                let der = [&X25519_PKCS8_PREFIX[..], secret.as_bytes()].concat();
+                pem::encode_string("PRIVATE KEY", LineEnding::default(), &der)
+                    .map_err(|err| format!("Failed to encode private key: {}", err))
+            }
+            PrivateKey::Secp256k1(secret) => {
+                k256::SecretKey::from_slice(secret.to_be_bytes().as_secret())
+                    .map_err(|err| format!("Invalid secp256k1 key: {}", err))?
+                    .to_pkcs8_pem(LineEnding::default())
+                    .map(|pem| pem.to_string())
+                    .map_err(|err| format!("Failed to encode private key: {}", err))
+            }
+        }
+    }
+
+    pub fn public_key(&self) -> PublicKey {
+        match self {
+            PrivateKey::Rsa(key) => PublicKey::Rsa(RsaPublicKey::from(key.as_ref())),
+            PrivateKey::X25519(secret) => PublicKey::X25519(X25519PublicKey::from(secret)),
+            PrivateKey::Secp256k1(secret) => PublicKey::Secp256k1(secret.public_key()),
+        }
+    }
+}
+
+/// The symmetric key wrapped for a single recipient.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RecipientSlot {
+    pub role: Role,
+    /// Hex SHA-256 of the recipient's DER encoded public key.
+    pub fingerprint: String,
+    pub algorithm: String,
+    /// Base64 encoded wrapped key.
+    pub wrapped_key: String,
+}
+
+/// What `process-data` writes to `enc_sym_keys.b64`: the AES key wrapped
+/// separately for every party allowed to decrypt the dataset.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct KeyEnvelope {
+    pub version: u8,
+    pub recipients: Vec<RecipientSlot>,
+}
+
+impl KeyEnvelope {
+    /// Wraps `symmetric_key` once per recipient: with RSA-OAEP (SHA-256) for
+    /// RSA keys, ephemeral-static ECDH with HKDF-SHA256 and AES-256-GCM for
+    /// X25519 keys and an Umbral capsule for secp256k1 keys.
+    pub fn seal(symmetric_key: &[u8], recipients: &[(Role, PublicKey)]) -> Result<Self, String> {
+        let mut slots = Vec::with_capacity(recipients.len());
+        for (role, public_key) in recipients {
+            let (algorithm, wrapped_key) = wrap(symmetric_key, public_key)?;
+            slots.push(RecipientSlot {
+                role: *role,
+                fingerprint: public_key.fingerprint()?,
+                algorithm: algorithm.to_string(),
+                wrapped_key: BASE64_STANDARD.encode(wrapped_key),
+            });
+        }
+        Ok(KeyEnvelope {
+            version: ENVELOPE_VERSION,
+            recipients: slots,
+        })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        serde_json::to_vec(self).unwrap()
+    }
+
+    /// Parses an uploaded envelope. Unlike stored ones, it must be an actual
+    /// envelope without PKCS#1 v1.5 slots, which the node would otherwise try
+    /// to unwrap for whoever uploaded them.
+    pub fn from_upload(bytes: &[u8]) -> Result<Self, String> {
+        if bytes.first() != Some(&b'{') {
+            return Err("Key envelope expected, re-run process-data to create one".to_string());
+        }
+        let envelope = Self::from_bytes(bytes)?;
+        if envelope
+            .recipients
+            .iter()
+            .any(|slot| slot.algorithm == RSA_PKCS1V15)
+        {
+            return Err(format!(
+                "Key envelope wraps a key with {}, re-run process-data to use {}",
+                RSA_PKCS1V15, RSA_OAEP_SHA256
+            ));
+        }
+        Ok(envelope)
+    }
+
+    /// Parses an envelope stored on the node; anything that is not one is
+    /// taken to be a key wrapped for the node alone, as uploaded before
+    /// envelopes existed.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, String> {
+        if bytes.first() == Some(&b'{') {
+            let envelope: KeyEnvelope = serde_json::from_slice(bytes)
+                .map_err(|err| format!("Invalid key envelope: {}", err))?;
+            if envelope.version > ENVELOPE_VERSION {
+                return Err(format!(
+                    "Unsupported key envelope version {}",
+                    envelope.version
+                ));
+            }
+            return Ok(envelope);
+        }
+        Ok(KeyEnvelope {
+            version: 0,
+            recipients: vec![RecipientSlot {
+                role: Role::Node,
+                fingerprint: String::new(),
+                algorithm: RSA_PKCS1V15.to_string(),
+                wrapped_key: BASE64_STANDARD.encode(bytes),
+            }],
+        })
+    }
+
+    pub fn slot_for(&self, fingerprint: &str) -> Option<&RecipientSlot> {
+        self.recipients
+            .iter()
+            .find(|slot| slot.fingerprint == fingerprint)
+    }
+
+    /// The owner's Umbral slot, which the node re-encrypts for buyers.
+    pub fn proxy_slot(&self) -> Option<&RecipientSlot> {
+        self.recipients
+            .iter()
+            .find(|slot| slot.role == Role::Owner && slot.algorithm == UMBRAL_PRE)
+    }
+
+    /// Unwraps the slot addressed to `private_key`.
+    pub fn open(&self, private_key: &PrivateKey) -> Result<Vec<u8>, String> {
+        let fingerprint = private_key.public_key().fingerprint()?;
+        let slot = self
+            .slot_for(&fingerprint)
+            .or_else(|| {
+                // Legacy envelopes carry no fingerprint
+                (self.version == 0).then(|| &self.recipients[0])
+            })
+            .ok_or_else(|| format!("No key slot for recipient {}", fingerprint))?;
+        let wrapped_key = BASE64_STANDARD
+            .decode(&slot.wrapped_key)
+            .map_err(|err| format!("Invalid wrapped key: {}", err))?;
+        unwrap(&slot.algorithm, &wrapped_key, private_key)
+    }
+}
+
+fn wrap(symmetric_key: &[u8], public_key: &PublicKey) -> Result<(&'static str, Vec<u8>), String> {
+    match public_key {
+        PublicKey::Rsa(key) => key
+            .encrypt(&mut OsRng, Oaep::new::<Sha256>(), symmetric_key)
+            .map(|wrapped| (RSA_OAEP_SHA256, wrapped))
+            .map_err(|err| format!("Failed to wrap key: {}", err)),
+        PublicKey::X25519(key) => {
+            // wrapped = ephemeral public key | nonce | ciphertext+tag
+            let ephemeral = EphemeralSecret::random_from_rng(OsRng);
+            let ephemeral_public = X25519PublicKey::from(&ephemeral);
+            let shared = ephemeral.diffie_hellman(key);
+            let cipher = x25519_cipher(shared.as_bytes(), &ephemeral_public, key)?;
+            let nonce: [u8; 12] = rand::thread_rng().gen();
+            let ciphertext = cipher
+                .encrypt(
+                    Nonce::from_slice(&nonce),
+                    Payload {
+                        msg: symmetric_key,
+                        aad: key.as_bytes(),
+                    },
+                )
+                .map_err(|_| "Failed to wrap key".to_string())?;
+            Ok((
+                X25519_HKDF_AES256GCM,
+                [ephemeral_public.as_bytes(), &nonce[..], &ciphertext].concat(),
+            ))
+        }
+        PublicKey::Secp256k1(key) => {
+            let (capsule, ciphertext) = umbral_pre::encrypt(key, symmetric_key)
+                .map_err(|err| format!("Failed to wrap key: {}", err))?;
+            Ok((UMBRAL_PRE, join_umbral(&capsule, &ciphertext)?))
+        }
+    }
+}
+
+/// wrapped = capsule length (u16 BE) | capsule | ciphertext
+fn join_umbral(capsule: &Capsule, ciphertext: &[u8]) -> Result<Vec<u8>, String> {
+    let capsule = capsule
+        .to_bytes()
+        .map_err(|err| format!("Failed to encode capsule: {}", err))?;
+    let capsule_len = u16::try_from(capsule.len()).map_err(|_| "Capsule too large")?;
+    Ok([&capsule_len.to_be_bytes()[..], &capsule, ciphertext].concat())
+}
+
+/// Splits an `umbral-pre-secp256k1` wrapped key into its capsule and ciphertext.
+pub fn split_umbral(wrapped_key: &[u8]) -> Result<(Capsule, &[u8]), String> {
+    if wrapped_key.len() < 2 {
+        return Err("Truncated wrapped key".to_string());
+    }
+    let capsule_len = u16::from_be_bytes([wrapped_key[0], wrapped_key[1]]) as usize;
+    if wrapped_key.len() < 2 + capsule_len {
+        return Err("Truncated wrapped key".to_string());
+    }
+    let (capsule, ciphertext) = wrapped_key[2..].split_at(capsule_len);
+    let capsule =
+        Capsule::from_bytes(capsule).map_err(|err| format!("Invalid capsule: {}", err))?;
+    Ok((capsule, ciphertext))
+}
+
+fn unwrap(
+    algorithm: &str,
+    wrapped_key: &[u8],
+    private_key: &PrivateKey,
+) -> Result<Vec<u8>, String> {
+    match (algorithm, private_key) {
+        (RSA_OAEP_SHA256, PrivateKey::Rsa(key)) => key
+            .decrypt(Oaep::new::<Sha256>(), wrapped_key)
+            .map_err(|_| "Failed to unwrap key".to_string()),
+        (RSA_PKCS1V15, PrivateKey::Rsa(key)) => key
+            .decrypt(Pkcs1v15Encrypt, wrapped_key)
+            .map_err(|_| "Failed to unwrap key".to_string()),
+        (X25519_HKDF_AES256GCM, PrivateKey::X25519(secret)) => {
+            if wrapped_key.len() < 32 + 12 {
+                return Err("Truncated wrapped key".to_string());
+            }
+            let (ephemeral_public, rest) = wrapped_key.split_at(32);
+            let (nonce, ciphertext) = rest.split_at(12);
+            let ephemeral_public =
+                X25519PublicKey::from(<[u8; 32]>::try_from(ephemeral_public).unwrap());
+            let recipient_public = X25519PublicKey::from(secret);
+            let shared = secret.diffie_hellman(&ephemeral_public);
+            let cipher = x25519_cipher(shared.as_bytes(), &ephemeral_public, &recipient_public)?;
+            cipher
+                .decrypt(
+                    Nonce::from_slice(nonce),
+                    Payload {
+                        msg: ciphertext,
+                        aad: recipient_public.as_bytes(),
+                    },
+                )
+                .map_err(|_| "Failed to unwrap key".to_string())
+        }
+        (UMBRAL_PRE, PrivateKey::Secp256k1(secret)) => {
+            let (capsule, ciphertext) = split_umbral(wrapped_key)?;
+            umbral_pre::decrypt_original(secret, &capsule, ciphertext)
+                .map(|key| key.to_vec())
+                .map_err(|_| "Failed to unwrap key".to_string())
+        }
+        _ => Err(format!(
+            "Wrapping algorithm {} does not match the private key type",
+            algorithm
+        )),
+    }
+}
+
+/// AES-256-GCM keyed by HKDF-SHA256 over the ECDH secret, salted with both
+/// public keys so a wrapped key is bound to its recipient.
+fn x25519_cipher(
+    shared_secret: &[u8],
+    ephemeral_public: &X25519PublicKey,
+    recipient_public: &X25519PublicKey,
+) -> Result<Aes256Gcm, String> {
+    let salt = [
+        &ephemeral_public.as_bytes()[..],
+        &recipient_public.as_bytes()[..],
+    ]
+    .concat();
+    let mut key = [0u8; 32];
+    Hkdf::<Sha256>::new(Some(&salt), shared_secret)
+        .expand(X25519_HKDF_INFO, &mut key)
+        .map_err(|_| "Failed to derive wrapping key".to_string())?;
+    Aes256Gcm::new_from_slice(&key).map_err(|_| "Invalid wrapping key".to_string())
+}
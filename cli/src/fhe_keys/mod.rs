@@ -0,0 +1,198 @@
+use base64::engine::general_purpose::STANDARD as BASE64_STANDARD;
+use base64::Engine;
+use lazy_static::lazy_static;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use tfhe::integer::{RadixClientKey, ServerKey};
+
+/// Where `process-data` keeps the FHE key pairs it generates, one directory per key id.
+const CLIENT_KEY_ROOT: &str = "keys/fhe";
+/// Where zen-node keeps the server keys owners uploaded, one directory per owner.
+const SERVER_KEY_ROOT: &str = "serverkeys";
+/// Server keys kept deserialized at once, each tens to hundreds of MB; the
+/// least recently used one is dropped past it.
+const MAX_CACHED_SERVER_KEYS: usize = 8;
+
+lazy_static! {
+    static ref SERVER_KEYS: Mutex<ServerKeyCache> = Mutex::new(ServerKeyCache::default());
+}
+
+/// Deserialized server keys by `<owner>/<key id>`, with the tick they were
+/// last used at.
+#[derive(Default)]
+struct ServerKeyCache {
+    keys: HashMap<String, (u64, Arc<ServerKey>)>,
+    tick: u64,
+}
+
+impl ServerKeyCache {
+    fn get(&mut self, cache_key: &str) -> Option<Arc<ServerKey>> {
+        self.tick += 1;
+        let tick = self.tick;
+        self.keys.get_mut(cache_key).map(|(used, server_key)| {
+            *used = tick;
+            server_key.clone()
+        })
+    }
+
+    fn insert(&mut self, cache_key: String, server_key: Arc<ServerKey>) {
+        self.tick += 1;
+        self.keys.insert(cache_key, (self.tick, server_key));
+        while self.keys.len() > MAX_CACHED_SERVER_KEYS {
+            let oldest = self
+                .keys
+                .iter()
+                .min_by_key(|(_, (used, _))| *used)
+                .map(|(cache_key, _)| cache_key.clone())
+                .unwrap();
+            self.keys.remove(&oldest);
+        }
+    }
+}
+
+/// Server keys are addressed by the hash of their serialization, so the same
+/// key uploaded twice lands in the same place.
+pub fn key_id(serialized_server_key: &[u8]) -> String {
+    let digest = Sha256::digest(serialized_server_key);
+    format!("{:x}", digest)[..32].to_string()
+}
+
+/// Errors unless `key_id` looks like one [`key_id`] produces: 32 lowercase
+/// hex digits. Key ids arrive in uploaded containers and request headers and
+/// end up in paths, so nothing else may get through.
+pub fn check_key_id(key_id: &str) -> io::Result<()> {
+    if key_id.len() == 32
+        && key_id
+            .bytes()
+            .all(|byte| byte.is_ascii_digit() || (b'a'..=b'f').contains(&byte))
+    {
+        Ok(())
+    } else {
+        Err(invalid(format!("Invalid FHE key id {:?}", key_id)))
+    }
+}
+
+/// Persists a freshly generated key pair for reuse by later runs and by the
+/// client-side decrypt server, returning its key id.
+pub fn save_key_pair(client_key: &RadixClientKey, server_key: &ServerKey) -> io::Result<String> {
+    let serialized_server_key = bincode::serialize(server_key).map_err(invalid)?;
+    let key_id = key_id(&serialized_server_key);
+    let dir = PathBuf::from(CLIENT_KEY_ROOT).join(&key_id);
+    fs::create_dir_all(&dir)?;
+    fs::write(
+        dir.join("client_key.bin"),
+        bincode::serialize(client_key).map_err(invalid)?,
+    )?;
+    fs::write(dir.join("server_key.bin"), serialized_server_key)?;
+    Ok(key_id)
+}
+
+pub fn load_client_key(key_id: &str) -> io::Result<RadixClientKey> {
+    check_key_id(key_id)?;
+    let path = PathBuf::from(CLIENT_KEY_ROOT)
+        .join(key_id)
+        .join("client_key.bin");
+    let bytes = fs::read(&path).map_err(|err| {
+        io::Error::new(
+            err.kind(),
+            format!(
+                "FHE key {} not found at {}: {}",
+                key_id,
+                path.display(),
+                err
+            ),
+        )
+    })?;
+    bincode::deserialize(&bytes).map_err(invalid)
+}
+
+pub fn load_server_key_bytes(key_id: &str) -> io::Result<Vec<u8>> {
+    check_key_id(key_id)?;
+    fs::read(
+        PathBuf::from(CLIENT_KEY_ROOT)
+            .join(key_id)
+            .join("server_key.bin"),
+    )
+}
+
+/// Where the server key `key_id` uploaded by `owner` is kept.
+pub fn server_key_path(owner: &str, key_id: &str) -> io::Result<PathBuf> {
+    check_key_id(key_id)?;
+    Ok(PathBuf::from(SERVER_KEY_ROOT)
+        .join(owner)
+        .join(format!("{}.b64", key_id)))
+}
+
+/// Stores a base64 encoded server key uploaded by `owner`, after checking it
+/// deserializes, and returns its key id.
+pub fn store_server_key(owner: &str, encoded: &[u8]) -> io::Result<String> {
+    let bytes = BASE64_STANDARD.decode(encoded).map_err(invalid)?;
+    let server_key: ServerKey = bincode::deserialize(&bytes).map_err(invalid)?;
+    let key_id = key_id(&bytes);
+    let path = server_key_path(owner, &key_id)?;
+    if !path.exists() {
+        fs::create_dir_all(path.parent().unwrap())?;
+        fs::write(&path, BASE64_STANDARD.encode(&bytes))?;
+        log::info!("🔑 Stored FHE server key {} for {}", key_id, owner);
+    }
+    SERVER_KEYS
+        .lock()
+        .unwrap()
+        .insert(format!("{}/{}", owner, key_id), Arc::new(server_key));
+    Ok(key_id)
+}
+
+pub fn has_server_key(owner: &str, key_id: &str) -> io::Result<bool> {
+    Ok(server_key_path(owner, key_id)?.exists())
+}
+
+/// Server key `key_id` of `owner`, served from memory while recently used.
+pub fn server_key(owner: &str, key_id: &str) -> io::Result<Arc<ServerKey>> {
+    let cache_key = format!("{}/{}", owner, key_id);
+    if let Some(server_key) = SERVER_KEYS.lock().unwrap().get(&cache_key) {
+        return Ok(server_key);
+    }
+    let encoded = fs::read(server_key_path(owner, key_id)?).map_err(|err| {
+        io::Error::new(
+            err.kind(),
+            format!("FHE server key {} not uploaded by {}", key_id, owner),
+        )
+    })?;
+    let bytes = BASE64_STANDARD.decode(encoded).map_err(invalid)?;
+    let server_key = Arc::new(bincode::deserialize(&bytes).map_err(invalid)?);
+    SERVER_KEYS
+        .lock()
+        .unwrap()
+        .insert(cache_key, Arc::clone(&server_key));
+    Ok(server_key)
+}
+
+fn invalid<E>(err: E) -> io::Error
+where
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    io::Error::new(io::ErrorKind::InvalidData, err)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn server_key_paths_stay_under_the_owner() {
+        let key_id = key_id(b"server key");
+        assert_eq!(
+            server_key_path("0xabc", &key_id).unwrap(),
+            PathBuf::from(SERVER_KEY_ROOT)
+                .join("0xabc")
+                .join(format!("{}.b64", key_id))
+        );
+        assert!(server_key_path("0xabc", "../../store/0xabc/data/enc_sym_keys").is_err());
+        assert!(server_key_path("0xabc", "").is_err());
+        assert!(load_client_key("../client").is_err());
+    }
+}
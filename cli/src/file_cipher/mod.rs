@@ -0,0 +1,423 @@
+use aes::cipher::{block_padding::Pkcs7, BlockDecryptMut, BlockSizeUser, KeyIvInit};
+use aes::Aes256;
+use aes_gcm::aead::stream::{DecryptorBE32, EncryptorBE32};
+use aes_gcm::aead::{Aead, KeyInit, Payload};
+use aes_gcm::{Aes256Gcm, Nonce};
+use rand::Rng;
+use std::io::{self, Read, Write};
+
+/// Leading bytes of AEAD encrypted files; anything else is legacy AES-CBC.
+const MAGIC: &[u8; 4] = b"DZAE";
+/// Version 1 is a single AES-GCM message, version 2 a STREAM of chunks.
+const VERSION: u8 = 2;
+const NONCE_SIZE: usize = 12;
+/// STREAM-BE32 leaves 7 bytes of the nonce to the file, the rest is the
+/// chunk counter and the last-chunk flag.
+const NONCE_PREFIX_SIZE: usize = 7;
+const TAG_SIZE: usize = 16;
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Encrypts everything read from `reader` with AES-256-GCM in the STREAM
+/// construction, writing the result to `writer` one chunk at a time.
+///
+/// Layout: `MAGIC | version: u8 | nonce prefix | chunk_size: u32 LE | ext_len: u8
+/// | extension | chunks`, each chunk being at most `chunk_size` bytes of
+/// ciphertext plus its tag. The header is bound as associated data of every
+/// chunk, and the final chunk is flagged, so reordered, dropped or truncated
+/// chunks fail decryption just like changed ciphertext.
+pub fn encrypt<R: Read, W: Write>(
+    mut reader: R,
+    mut writer: W,
+    key: &[u8],
+    extension: &str,
+) -> Result<(), String> {
+    check_extension(extension)?;
+    let nonce_prefix: [u8; NONCE_PREFIX_SIZE] = rand::thread_rng().gen();
+    let mut header = Vec::with_capacity(MAGIC.len() + 6 + NONCE_PREFIX_SIZE + extension.len());
+    header.extend_from_slice(MAGIC);
+    header.push(VERSION);
+    header.extend_from_slice(&nonce_prefix);
+    header.extend_from_slice(&(CHUNK_SIZE as u32).to_le_bytes());
+    header.push(extension.len() as u8);
+    header.extend_from_slice(extension.as_bytes());
+    writer.write_all(&header).map_err(write_error)?;
+
+    let cipher = Aes256Gcm::new_from_slice(key).map_err(|_| "Invalid key length".to_string())?;
+    let mut encryptor = EncryptorBE32::from_aead(cipher, nonce_prefix.as_slice().into());
+    // One chunk of lookahead tells whether the current chunk is the last one
+    let mut chunk = vec![0u8; CHUNK_SIZE];
+    let mut next = vec![0u8; CHUNK_SIZE];
+    let mut chunk_len = read_chunk(&mut reader, &mut chunk).map_err(read_error)?;
+    loop {
+        let next_len = read_chunk(&mut reader, &mut next).map_err(read_error)?;
+        let payload = Payload {
+            msg: &chunk[..chunk_len],
+            aad: &header,
+        };
+        if next_len == 0 {
+            let ciphertext = encryptor
+                .encrypt_last(payload)
+                .map_err(|_| "Encryption failed".to_string())?;
+            writer.write_all(&ciphertext).map_err(write_error)?;
+            break;
+        }
+        let ciphertext = encryptor
+            .encrypt_next(payload)
+            .map_err(|_| "Encryption failed".to_string())?;
+        writer.write_all(&ciphertext).map_err(write_error)?;
+        std::mem::swap(&mut chunk, &mut next);
+        chunk_len = next_len;
+    }
+    writer.flush().map_err(write_error)
+}
+
+/// Decrypts a file produced by [`encrypt`], by the single-message AES-GCM
+/// format or by the legacy AES-CBC format into `writer`, returning the
+/// original file extension.
+///
+/// Only chunked files are decrypted in constant memory; the older formats
+/// are read whole. Nothing written before an error can be trusted.
+pub fn decrypt<R: Read, W: Write>(
+    mut reader: R,
+    mut writer: W,
+    key: &[u8],
+) -> Result<String, String> {
+    let mut prelude = [0u8; MAGIC.len() + 1];
+    let prelude_len = read_chunk(&mut reader, &mut prelude).map_err(read_error)?;
+    if prelude_len < prelude.len() || !prelude.starts_with(MAGIC) || prelude[MAGIC.len()] == 1 {
+        let mut encrypted_data = prelude[..prelude_len].to_vec();
+        reader
+            .read_to_end(&mut encrypted_data)
+            .map_err(read_error)?;
+        let (decrypted_data, file_extension) = if encrypted_data.starts_with(MAGIC) {
+            decrypt_single(&encrypted_data, key)?
+        } else {
+            decrypt_cbc(&encrypted_data, key)?
+        };
+        writer.write_all(&decrypted_data).map_err(write_error)?;
+        return Ok(file_extension);
+    }
+    let version = prelude[MAGIC.len()];
+    if version != VERSION {
+        return Err(format!("Unsupported encrypted file version {}", version));
+    }
+
+    let mut fixed = [0u8; NONCE_PREFIX_SIZE + 5];
+    read_exact(&mut reader, &mut fixed)?;
+    let nonce_prefix = &fixed[..NONCE_PREFIX_SIZE];
+    let chunk_size = u32::from_le_bytes(
+        fixed[NONCE_PREFIX_SIZE..NONCE_PREFIX_SIZE + 4]
+            .try_into()
+            .unwrap(),
+    ) as usize;
+    if chunk_size == 0 || chunk_size > 16 * CHUNK_SIZE {
+        return Err(format!("Invalid chunk size {}", chunk_size));
+    }
+    let mut extension = vec![0u8; fixed[NONCE_PREFIX_SIZE + 4] as usize];
+    read_exact(&mut reader, &mut extension)?;
+    let header = [&prelude[..], &fixed[..], &extension[..]].concat();
+    let file_extension =
+        String::from_utf8(extension).map_err(|_| "Invalid file extension".to_string())?;
+    check_extension(&file_extension)?;
+
+    let cipher = Aes256Gcm::new_from_slice(key).map_err(|_| "Invalid key length".to_string())?;
+    let mut decryptor = DecryptorBE32::from_aead(cipher, nonce_prefix.into());
+    let mut chunk = vec![0u8; chunk_size + TAG_SIZE];
+    let mut next = vec![0u8; chunk_size + TAG_SIZE];
+    let mut chunk_len = read_chunk(&mut reader, &mut chunk).map_err(read_error)?;
+    if chunk_len < TAG_SIZE {
+        return Err("Truncated encrypted file".to_string());
+    }
+    loop {
+        let next_len = read_chunk(&mut reader, &mut next).map_err(read_error)?;
+        let payload = Payload {
+            msg: &chunk[..chunk_len],
+            aad: &header,
+        };
+        if next_len == 0 {
+            let plaintext = decryptor.decrypt_last(payload).map_err(|_| tampered())?;
+            writer.write_all(&plaintext).map_err(write_error)?;
+            break;
+        }
+        let plaintext = decryptor.decrypt_next(payload).map_err(|_| tampered())?;
+        writer.write_all(&plaintext).map_err(write_error)?;
+        std::mem::swap(&mut chunk, &mut next);
+        chunk_len = next_len;
+    }
+    writer.flush().map_err(write_error)?;
+    Ok(file_extension)
+}
+
+/// Errors unless `extension` is safe to append to a file name: up to 255
+/// ASCII letters and digits, or nothing. Decrypted files are named after it.
+fn check_extension(extension: &str) -> Result<(), String> {
+    if extension.len() <= u8::MAX as usize
+        && extension.bytes().all(|byte| byte.is_ascii_alphanumeric())
+    {
+        Ok(())
+    } else {
+        Err(format!("Invalid file extension {:?}", extension))
+    }
+}
+
+/// Fills `buf` as far as the reader allows, returning how much was read;
+/// less than `buf.len()` only at the end of the input.
+fn read_chunk<R: Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(filled)
+}
+
+fn read_exact<R: Read>(reader: &mut R, buf: &mut [u8]) -> Result<(), String> {
+    if read_chunk(reader, buf).map_err(read_error)? < buf.len() {
+        return Err("Truncated encrypted file".to_string());
+    }
+    Ok(())
+}
+
+fn read_error(err: io::Error) -> String {
+    format!("Failed to read file: {}", err)
+}
+
+fn tampered() -> String {
+    "Encrypted file failed authentication, it was tampered with".to_string()
+}
+
+fn write_error(err: io::Error) -> String {
+    format!("Failed to write file: {}", err)
+}
+
+/// Files encrypted as a single AES-GCM message:
+/// `MAGIC | 1 | nonce | ext_len: u8 | extension | ciphertext+tag`.
+fn decrypt_single(encrypted_data: &[u8], key: &[u8]) -> Result<(Vec<u8>, String), String> {
+    let prelude = MAGIC.len() + 1 + NONCE_SIZE + 1;
+    if encrypted_data.len() < prelude {
+        return Err("Truncated encrypted file".to_string());
+    }
+    let nonce = &encrypted_data[MAGIC.len() + 1..MAGIC.len() + 1 + NONCE_SIZE];
+    let extension_len = encrypted_data[prelude - 1] as usize;
+    if encrypted_data.len() < prelude + extension_len {
+        return Err("Truncated encrypted file".to_string());
+    }
+    let (header, ciphertext) = encrypted_data.split_at(prelude + extension_len);
+    let file_extension = std::str::from_utf8(&header[prelude..])
+        .map_err(|_| "Invalid file extension".to_string())?
+        .to_string();
+    check_extension(&file_extension)?;
+
+    let cipher = Aes256Gcm::new_from_slice(key).map_err(|_| "Invalid key length".to_string())?;
+    let decrypted_data = cipher
+        .decrypt(
+            Nonce::from_slice(nonce),
+            Payload {
+                msg: ciphertext,
+                aad: header,
+            },
+        )
+        .map_err(|_| tampered())?;
+    Ok((decrypted_data, file_extension))
+}
+
+/// Files encrypted before authenticated encryption: `iv | len:ext | ciphertext`
+/// with AES-256-CBC and PKCS7 padding.
+fn decrypt_cbc(encrypted_data: &[u8], key: &[u8]) -> Result<(Vec<u8>, String), String> {
+    log::warn!("Decrypting an unauthenticated AES-CBC file, re-encrypt it with process-data");
+    let iv_size = Aes256::block_size();
+    if encrypted_data.len() < iv_size {
+        return Err("Truncated encrypted file".to_string());
+    }
+    let (iv, rest) = encrypted_data.split_at(iv_size);
+
+    let metadata_len_end = rest
+        .iter()
+        .position(|&b| b == b':')
+        .ok_or("Missing file metadata")?;
+    let metadata_len: usize = std::str::from_utf8(&rest[..metadata_len_end])
+        .ok()
+        .and_then(|len| len.parse().ok())
+        .ok_or("Invalid file metadata")?;
+    let metadata_end = metadata_len_end + 1 + metadata_len;
+    if rest.len() < metadata_end {
+        return Err("Truncated encrypted file".to_string());
+    }
+
+    let file_extension = std::str::from_utf8(&rest[metadata_len_end + 1..metadata_end])
+        .map_err(|_| "Invalid file extension".to_string())?
+        .to_string();
+    check_extension(&file_extension)?;
+    let encrypted_data = &rest[metadata_end..];
+
+    let cipher = cbc::Decryptor::<Aes256>::new_from_slices(key, iv)
+        .map_err(|_| "Invalid key length".to_string())?;
+    let mut buffer = encrypted_data.to_vec();
+    let decrypted_data = cipher
+        .decrypt_padded_mut::<Pkcs7>(&mut buffer)
+        .map_err(|_| "Failed to decrypt file".to_string())?;
+    Ok((decrypted_data.to_vec(), file_extension))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use aes::cipher::BlockEncryptMut;
+
+    const KEY: [u8; 32] = [7; 32];
+
+    /// Data spanning `chunks` chunks, the last one partial.
+    fn data(chunks: usize) -> Vec<u8> {
+        (0..(chunks - 1) * CHUNK_SIZE + 1000)
+            .map(|i| (i % 251) as u8)
+            .collect()
+    }
+
+    fn encrypted(data: &[u8]) -> Vec<u8> {
+        let mut encrypted = Vec::new();
+        encrypt(data, &mut encrypted, &KEY, "csv").unwrap();
+        encrypted
+    }
+
+    /// Length of the header `encrypt` writes for the `csv` extension.
+    fn header_len() -> usize {
+        MAGIC.len() + 1 + NONCE_PREFIX_SIZE + 4 + 1 + "csv".len()
+    }
+
+    fn decrypted(encrypted: &[u8]) -> Result<(Vec<u8>, String), String> {
+        let mut data = Vec::new();
+        let extension = decrypt(encrypted, &mut data, &KEY)?;
+        Ok((data, extension))
+    }
+
+    #[test]
+    fn round_trips_chunked_files() {
+        for chunks in [1, 3] {
+            let data = data(chunks);
+            let (decrypted, extension) = decrypted(&encrypted(&data)).unwrap();
+            assert_eq!(decrypted, data);
+            assert_eq!(extension, "csv");
+        }
+        assert_eq!(decrypted(&encrypted(&[])).unwrap().0, Vec::<u8>::new());
+    }
+
+    #[test]
+    fn rejects_a_tampered_chunk() {
+        let mut encrypted = encrypted(&data(3));
+        encrypted[header_len() + CHUNK_SIZE + 100] ^= 1;
+        assert_eq!(decrypted(&encrypted).unwrap_err(), tampered());
+    }
+
+    #[test]
+    fn rejects_a_tampered_header() {
+        let mut encrypted = encrypted(&data(2));
+        let last = header_len() - 1;
+        encrypted[last] = b'x';
+        assert_eq!(decrypted(&encrypted).unwrap_err(), tampered());
+    }
+
+    #[test]
+    fn rejects_a_truncated_stream() {
+        let encrypted = encrypted(&data(3));
+        // Dropping the last chunk leaves a stream whose final chunk is not
+        // flagged as such
+        let without_last = header_len() + 2 * (CHUNK_SIZE + TAG_SIZE);
+        assert_eq!(
+            decrypted(&encrypted[..without_last]).unwrap_err(),
+            tampered()
+        );
+        assert_eq!(
+            decrypted(&encrypted[..encrypted.len() - 1]).unwrap_err(),
+            tampered()
+        );
+        assert!(decrypted(&encrypted[..header_len() + 4])
+            .unwrap_err()
+            .contains("Truncated"));
+    }
+
+    #[test]
+    fn rejects_reordered_chunks() {
+        let encrypted = encrypted(&data(3));
+        let chunk = CHUNK_SIZE + TAG_SIZE;
+        let (header, chunks) = encrypted.split_at(header_len());
+        let reordered = [
+            header,
+            &chunks[chunk..2 * chunk],
+            &chunks[..chunk],
+            &chunks[2 * chunk..],
+        ]
+        .concat();
+        assert_eq!(decrypted(&reordered).unwrap_err(), tampered());
+    }
+
+    #[test]
+    fn refuses_unsafe_extensions() {
+        for extension in ["x/../../tmp", "..", "tar.gz", "a\\b", "csv\0"] {
+            let err = encrypt(&b"data"[..], Vec::new(), &KEY, extension).unwrap_err();
+            assert!(err.contains("Invalid file extension"), "{}", extension);
+        }
+        assert!(encrypt(&b"data"[..], Vec::new(), &KEY, "").is_ok());
+        assert!(encrypt(&b"data"[..], Vec::new(), &KEY, &"x".repeat(256)).is_err());
+
+        // Checked before anything is decrypted, whatever wrote the header
+        let mut encrypted = Vec::new();
+        encrypt(&b"data"[..], &mut encrypted, &KEY, "abcdefgh").unwrap();
+        let start = header_len() - "csv".len();
+        encrypted[start..start + 8].copy_from_slice(b"x/../../");
+        let mut data = Vec::new();
+        let err = decrypt(&encrypted[..], &mut data, &KEY).unwrap_err();
+        assert!(err.contains("Invalid file extension"), "{}", err);
+        assert!(data.is_empty());
+
+        let iv = [5u8; 16];
+        let file = [&iv[..], b"5:../..", &[0u8; 16]].concat();
+        assert!(decrypted(&file)
+            .unwrap_err()
+            .contains("Invalid file extension"));
+    }
+
+    #[test]
+    fn reads_single_message_files() {
+        let nonce = [3u8; NONCE_SIZE];
+        let header = [&MAGIC[..], &[1], &nonce, &[3], b"csv"].concat();
+        let ciphertext = Aes256Gcm::new_from_slice(&KEY)
+            .unwrap()
+            .encrypt(
+                Nonce::from_slice(&nonce),
+                Payload {
+                    msg: b"a,b\n1,2\n",
+                    aad: &header,
+                },
+            )
+            .unwrap();
+        let mut file = [header, ciphertext].concat();
+        assert_eq!(
+            decrypted(&file).unwrap(),
+            (b"a,b\n1,2\n".to_vec(), "csv".to_string())
+        );
+        *file.last_mut().unwrap() ^= 1;
+        assert_eq!(decrypted(&file).unwrap_err(), tampered());
+    }
+
+    #[test]
+    fn reads_cbc_files() {
+        let iv = [5u8; 16];
+        let data = b"a,b\n1,2\n";
+        let mut buffer = [0u8; 16];
+        buffer[..data.len()].copy_from_slice(data);
+        let ciphertext = cbc::Encryptor::<Aes256>::new_from_slices(&KEY, &iv)
+            .unwrap()
+            .encrypt_padded_mut::<Pkcs7>(&mut buffer, data.len())
+            .unwrap()
+            .to_vec();
+        let file = [&iv[..], b"3:csv", &ciphertext].concat();
+        assert_eq!(
+            decrypted(&file).unwrap(),
+            (b"a,b\n1,2\n".to_vec(), "csv".to_string())
+        );
+    }
+}
@@ -1,30 +1,41 @@
-use clap::Parser;
+use crate::envelope::PrivateKey;
+use clap::{Parser, ValueEnum};
 use rand::rngs::OsRng;
-use rsa::{
-    pkcs8::{EncodePrivateKey, EncodePublicKey, LineEnding},
-    RsaPrivateKey, RsaPublicKey,
-};
+use rsa::RsaPrivateKey;
 use std::fs::{self, File};
 use std::io::Write;
 
 #[derive(Debug, Clone, Parser)]
-pub struct KeygenCmd;
+pub struct KeygenCmd {
+    /// Key type, decides how AES keys are wrapped for this key holder
+    #[arg(short, long, value_enum, default_value_t = KeyAlgorithm::Rsa)]
+    algorithm: KeyAlgorithm,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum KeyAlgorithm {
+    /// RSA-2048, AES keys are wrapped with RSA-OAEP (SHA-256)
+    Rsa,
+    /// X25519, AES keys are wrapped with ECDH + HKDF-SHA256 + AES-256-GCM
+    X25519,
+    /// secp256k1, AES keys are wrapped with Umbral so access can be granted
+    /// through proxy re-encryption
+    Secp256k1,
+}
 
 impl KeygenCmd {
     pub async fn execute(&self) -> Result<(), String> {
-        log::info!("Generating AES keys");
-        gen_and_save_keys();
-        Ok(())
+        log::info!("Generating {:?} keys", self.algorithm);
+        gen_and_save_keys(self.algorithm)
     }
 }
 
-fn generate_rsa_keys() -> (RsaPrivateKey, RsaPublicKey) {
+fn generate_rsa_keys() -> PrivateKey {
     let mut rng = OsRng;
     let bits = 2048;
     let private_key = RsaPrivateKey::new(&mut rng, bits).expect("failed to generate a key");
-    let public_key = RsaPublicKey::from(&private_key);
 
-    (private_key, public_key)
+    PrivateKey::Rsa(Box::new(private_key))
 }
 
 fn save_key_to_file(filename: &str, key: &[u8]) {
@@ -32,23 +43,19 @@ fn save_key_to_file(filename: &str, key: &[u8]) {
     file.write_all(key).expect("unable to write data");
 }
 
-fn gen_and_save_keys() {
+fn gen_and_save_keys(algorithm: KeyAlgorithm) -> Result<(), String> {
     fs::create_dir_all("keys/").expect("unable to create directory");
-    let (private_key1, public_key1) = generate_rsa_keys();
+    let private_key1 = match algorithm {
+        KeyAlgorithm::Rsa => generate_rsa_keys(),
+        KeyAlgorithm::X25519 => PrivateKey::generate_x25519(),
+        KeyAlgorithm::Secp256k1 => PrivateKey::generate_secp256k1(),
+    };
 
-    save_key_to_file(
-        "keys/private_key.pem",
-        &private_key1
-            .to_pkcs8_pem(LineEnding::default())
-            .unwrap()
-            .as_bytes(),
-    );
+    save_key_to_file("keys/private_key.pem", private_key1.to_pem()?.as_bytes());
     save_key_to_file(
         "keys/public_key.pem",
-        &public_key1
-            .to_public_key_pem(LineEnding::default())
-            .unwrap()
-            .as_bytes(),
+        private_key1.public_key().to_pem()?.as_bytes(),
     );
     log::info!("Saved Keys to ./keys/");
+    Ok(())
 }
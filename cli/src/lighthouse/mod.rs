@@ -1,69 +1,85 @@
+use crate::storage::{download, kubo, other, ObjectStat, StorageBackend, StoredObject};
 use dotenv::dotenv;
-use reqwest::multipart;
 use reqwest::Client;
-use serde::Deserialize;
-use std::error::Error;
-use std::fs::create_dir_all;
-use std::fs::File;
-use std::io::Write;
-use std::io::{Read, Seek, SeekFrom};
+use serde_json::Value;
+use std::io;
 use std::path::Path;
-#[derive(Deserialize, Debug)]
-pub struct LighthouseResponse {
-    Name: String,
-    Hash: String,
-    Size: String,
-}
 
-pub async fn upload_file(base64_file_path: &str) -> Result<LighthouseResponse, Box<dyn Error>> {
-    log::info!("Backing Up file on Filecoin!! Your encrypted data isn't going anywhere now 😈");
-    let mut file = File::open(base64_file_path)?;
+const UPLOAD_URL: &str = "https://node.lighthouse.storage/api/v0/add";
+const GATEWAY_URL: &str = "https://gateway.lighthouse.storage/ipfs";
+const FILE_INFO_URL: &str = "https://api.lighthouse.storage/api/lighthouse/file_info";
 
-    dotenv().ok();
-    let lh_key = std::env::var("LH_API").expect("LH_API_TOKEN must be set.");
-    const SIZE_LIMIT: usize = 1 * 1024 * 1024;
-    let mut buffer = vec![0; SIZE_LIMIT];
-    let bytes_read = file.read(&mut buffer)?;
+/// Lighthouse, which keeps uploads on IPFS and makes Filecoin deals for them.
+pub struct Lighthouse {
+    api_key: String,
+    client: Client,
+}
 
-    if bytes_read < SIZE_LIMIT {
-        buffer.resize(bytes_read, 0);
+impl Lighthouse {
+    /// Reads the API key from `LH_API`, also looking in `.env`.
+    pub fn from_env() -> Result<Self, String> {
+        dotenv().ok();
+        let api_key = std::env::var("LH_API")
+            .map_err(|_| "LH_API must be set to back up to Lighthouse".to_string())?;
+        Ok(Lighthouse {
+            api_key,
+            client: Client::new(),
+        })
     }
+}
 
-    let temp_dir = std::env::temp_dir();
-    let temp_file_path = temp_dir.join("tempfile.txt");
-    let mut temp_file = File::create(&temp_file_path)?;
-    temp_file.write_all(&buffer)?;
-
-    // Create a new HTTP client
-    let client = Client::new();
-
-    // Create a multipart form with the chunk as a plain text file
-    let form = multipart::Form::new().part(
-        "file",
-        multipart::Part::bytes(buffer)
-            .file_name("tempfile.txt")
-            .mime_str("text/plain")?,
-    );
+#[rocket::async_trait]
+impl StorageBackend for Lighthouse {
+    fn name(&self) -> &'static str {
+        "lighthouse"
+    }
 
-    // Send the request
-    let request_url = "https://node.lighthouse.storage/api/v0/add";
-    println!("Sending POST request to {}", request_url);
+    async fn put(&self, path: &Path) -> io::Result<StoredObject> {
+        log::info!("Backing Up file on Filecoin!! Your encrypted data isn't going anywhere now 😈");
+        let request = self
+            .client
+            .post(UPLOAD_URL)
+            .header("Authorization", format!("Bearer {}", self.api_key));
+        let stored = kubo::add(request, path).await?;
+        log::info!("Your Lighthouse IPFS CID: {:?}", stored.id);
+        Ok(stored)
+    }
 
-    let response = client
-        .post(request_url)
-        .header("Authorization", format!("Bearer {}", lh_key))
-        .multipart(form)
-        .send()
-        .await?;
+    async fn get(&self, id: &str, dest: &Path) -> io::Result<u64> {
+        let response = self
+            .client
+            .get(format!("{}/{}", GATEWAY_URL, id))
+            .send()
+            .await
+            .map_err(other)?;
+        download(response, dest).await
+    }
 
-    // Check the status
-    if !response.status().is_success() {
-        log::error!("OOpss! Failed to upload file. Status: {}", response.status());
-        return Err("Failed to upload file".into());
+    /// Lighthouse retains everything uploaded with the account's key, so
+    /// pinning only checks that it knows the CID.
+    async fn pin(&self, id: &str) -> io::Result<()> {
+        self.stat(id).await.map(|_| ())
     }
-    let response_text = response.text().await?;
-    let lighthouse_response: LighthouseResponse = serde_json::from_str(&response_text)?;
-    log::info!("Your Lighthouse IPFS CID: {:?}", lighthouse_response.Hash);
 
-    Ok(lighthouse_response)
+    async fn stat(&self, id: &str) -> io::Result<ObjectStat> {
+        let info: Value = self
+            .client
+            .get(FILE_INFO_URL)
+            .query(&[("cid", id)])
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(other)?
+            .json()
+            .await
+            .map_err(other)?;
+        // Reported either as a number or as a numeric string
+        let size = match &info["fileSizeInBytes"] {
+            Value::Number(size) => size.as_u64(),
+            Value::String(size) => size.parse().ok(),
+            _ => None,
+        }
+        .ok_or_else(|| other(format!("Lighthouse has no file info for {}", id)))?;
+        Ok(ObjectStat { size })
+    }
 }
@@ -1,7 +1,19 @@
+mod access;
+mod auth;
+mod backup;
+mod catalog;
+mod container;
 mod decrypt;
+mod envelope;
+mod fhe_keys;
+mod file_cipher;
 mod keygen;
 mod lighthouse;
+mod pre;
 mod process;
+mod restore;
+mod share;
+mod storage;
 mod zen_node;
 mod zk_proof;
 use clap::Parser;
@@ -9,6 +21,7 @@ use env_logger::{Builder, Target};
 use keygen::KeygenCmd;
 use log::LevelFilter;
 use process::StoreCmd;
+use share::{GrantAccessCmd, OpenSharedCmd};
 use std::env;
 use zen_node::ZenNodeCmd;
 #[derive(Parser)]
@@ -23,6 +36,8 @@ enum Commands {
     KeyGen(KeygenCmd),
     ProcessData(StoreCmd),
     ZenNode(ZenNodeCmd),
+    GrantAccess(GrantAccessCmd),
+    OpenShared(OpenSharedCmd),
 }
 
 #[rocket::main]
@@ -51,5 +66,15 @@ async fn main() {
                 eprintln!("{}", error);
             }
         }
+        Commands::GrantAccess(grant_cmd) => {
+            if let Err(error) = grant_cmd.execute().await {
+                eprintln!("{}", error);
+            }
+        }
+        Commands::OpenShared(open_cmd) => {
+            if let Err(error) = open_cmd.execute().await {
+                eprintln!("{}", error);
+            }
+        }
     }
 }
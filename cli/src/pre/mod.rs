@@ -0,0 +1,210 @@
+use crate::catalog::{now, record_key, Catalog};
+use crate::envelope::{split_umbral, PrivateKey, PublicKey};
+use base64::engine::general_purpose::STANDARD as BASE64_STANDARD;
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use std::io;
+use umbral_pre::{CapsuleFrag, DefaultDeserialize, DefaultSerialize, KeyFrag, Signer};
+
+/// A re-encryption key the owner of a dataset issued for one buyer. It lets
+/// the node turn the owner's Umbral slot into something only the buyer can
+/// open, without the node being able to open it itself.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReencryptionKey {
+    pub owner: String,
+    pub file_id: String,
+    /// Fingerprint of the buyer's public key.
+    pub grantee: String,
+    /// PEM of the buyer's secp256k1 public key.
+    pub grantee_key: String,
+    /// PEM of the owner's secp256k1 public key the AES key is wrapped for.
+    pub delegating_key: String,
+    /// Base64 encoded Umbral key fragment.
+    pub kfrag: String,
+    pub created_at: u64,
+}
+
+impl ReencryptionKey {
+    pub fn new(
+        owner: &str,
+        file_id: &str,
+        delegating: &PublicKey,
+        grantee: &PublicKey,
+        kfrag: &[u8],
+    ) -> Result<Self, String> {
+        Ok(ReencryptionKey {
+            owner: owner.to_string(),
+            file_id: file_id.to_string(),
+            grantee: grantee.fingerprint()?,
+            grantee_key: grantee.to_pem()?,
+            delegating_key: delegating.to_pem()?,
+            kfrag: BASE64_STANDARD.encode(kfrag),
+            created_at: now(),
+        })
+    }
+}
+
+/// What the node answers on `/reencrypt/<address>/<filename>/<grantee>`.
+#[derive(Serialize, Deserialize)]
+pub struct ReencryptResult {
+    pub grantee: String,
+    pub delegating_key: String,
+    /// Base64 encoded owner slot of the key envelope.
+    pub wrapped_key: String,
+    /// Base64 encoded capsule fragment for the grantee.
+    pub capsule_frag: String,
+    pub cid: Option<String>,
+}
+
+/// Re-encryption keys held by the node, next to the catalog.
+#[derive(Clone)]
+pub struct ReencryptionKeys {
+    keys: sled::Tree,
+}
+
+impl ReencryptionKeys {
+    pub fn open(catalog: &Catalog) -> Result<Self, String> {
+        Ok(ReencryptionKeys {
+            keys: catalog.tree("reencryption_keys")?,
+        })
+    }
+
+    pub fn put(&self, key: &ReencryptionKey) -> io::Result<()> {
+        self.keys
+            .insert(
+                grant_key(&key.owner, &key.file_id, &key.grantee),
+                serde_json::to_vec(key)?,
+            )
+            .map_err(to_io)?;
+        self.keys.flush().map_err(to_io)?;
+        Ok(())
+    }
+
+    pub fn get(
+        &self,
+        owner: &str,
+        file_id: &str,
+        grantee: &str,
+    ) -> io::Result<Option<ReencryptionKey>> {
+        match self
+            .keys
+            .get(grant_key(owner, file_id, grantee))
+            .map_err(to_io)?
+        {
+            Some(value) => Ok(Some(serde_json::from_slice(&value)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn remove(
+        &self,
+        owner: &str,
+        file_id: &str,
+        grantee: &str,
+    ) -> io::Result<Option<ReencryptionKey>> {
+        let removed = self
+            .keys
+            .remove(grant_key(owner, file_id, grantee))
+            .map_err(to_io)?;
+        self.keys.flush().map_err(to_io)?;
+        match removed {
+            Some(value) => Ok(Some(serde_json::from_slice(&value)?)),
+            None => Ok(None),
+        }
+    }
+}
+
+fn grant_key(owner: &str, file_id: &str, grantee: &str) -> String {
+    format!("{}/{}", record_key(owner, file_id), grantee)
+}
+
+fn to_io(err: sled::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, err)
+}
+
+fn umbral_secret(key: &PrivateKey) -> Result<&umbral_pre::SecretKey, String> {
+    match key {
+        PrivateKey::Secp256k1(secret) => Ok(secret),
+        _ => Err("Proxy re-encryption needs a secp256k1 key (key-gen -a secp256k1)".to_string()),
+    }
+}
+
+fn umbral_public(key: &PublicKey) -> Result<&umbral_pre::PublicKey, String> {
+    match key {
+        PublicKey::Secp256k1(public) => Ok(public),
+        _ => Err("Proxy re-encryption needs a secp256k1 key (key-gen -a secp256k1)".to_string()),
+    }
+}
+
+/// Creates the key fragment letting the node re-encrypt the owner's slot for
+/// `grantee`. It is signed by the owner's key, which is how the node knows the
+/// owner issued it.
+pub fn issue(owner_key: &PrivateKey, grantee: &PublicKey) -> Result<Vec<u8>, String> {
+    let secret = umbral_secret(owner_key)?;
+    let kfrags = umbral_pre::generate_kfrags(
+        secret,
+        umbral_public(grantee)?,
+        &Signer::new(secret.clone()),
+        1,
+        1,
+        true,
+        true,
+    );
+    kfrags[0]
+        .to_bytes()
+        .map(|bytes| bytes.to_vec())
+        .map_err(|err| format!("Failed to encode key fragment: {}", err))
+}
+
+/// Checks that `kfrag` was issued by `delegating` for `grantee`.
+pub fn verify(kfrag: &[u8], delegating: &PublicKey, grantee: &PublicKey) -> Result<(), String> {
+    verified_kfrag(kfrag, delegating, grantee).map(|_| ())
+}
+
+fn verified_kfrag(
+    kfrag: &[u8],
+    delegating: &PublicKey,
+    grantee: &PublicKey,
+) -> Result<umbral_pre::VerifiedKeyFrag, String> {
+    let delegating = umbral_public(delegating)?;
+    KeyFrag::from_bytes(kfrag)
+        .map_err(|err| format!("Invalid key fragment: {}", err))?
+        .verify(delegating, Some(delegating), Some(umbral_public(grantee)?))
+        .map_err(|(err, _)| format!("Key fragment does not verify: {}", err))
+}
+
+/// Re-encrypts the capsule of the owner's `wrapped_key` for `grantee`. The
+/// result is only useful together with the grantee's private key.
+pub fn reencrypt(
+    wrapped_key: &[u8],
+    kfrag: &[u8],
+    delegating: &PublicKey,
+    grantee: &PublicKey,
+) -> Result<Vec<u8>, String> {
+    let kfrag = verified_kfrag(kfrag, delegating, grantee)?;
+    let (capsule, _) = split_umbral(wrapped_key)?;
+    umbral_pre::reencrypt(&capsule, kfrag)
+        .to_bytes()
+        .map(|bytes| bytes.to_vec())
+        .map_err(|err| format!("Failed to encode capsule fragment: {}", err))
+}
+
+/// Recovers the AES key from the owner's `wrapped_key` and the capsule
+/// fragment the node re-encrypted for `grantee_key`.
+pub fn open(
+    grantee_key: &PrivateKey,
+    delegating: &PublicKey,
+    wrapped_key: &[u8],
+    cfrag: &[u8],
+) -> Result<Vec<u8>, String> {
+    let secret = umbral_secret(grantee_key)?;
+    let delegating = umbral_public(delegating)?;
+    let (capsule, ciphertext) = split_umbral(wrapped_key)?;
+    let cfrag = CapsuleFrag::from_bytes(cfrag)
+        .map_err(|err| format!("Invalid capsule fragment: {}", err))?
+        .verify(&capsule, delegating, delegating, &secret.public_key())
+        .map_err(|(err, _)| format!("Capsule fragment does not verify: {}", err))?;
+    umbral_pre::decrypt_reencrypted(secret, delegating, &capsule, [cfrag], ciphertext)
+        .map(|key| key.to_vec())
+        .map_err(|err| format!("Failed to open re-encrypted key: {}", err))
+}
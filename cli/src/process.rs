@@ -1,37 +1,44 @@
-use aes::cipher::BlockSizeUser;
-use clap::{Arg, Command, Parser};
+use clap::{Arg, Command, Parser, ValueEnum};
 use dialoguer::theme::ColorfulTheme;
-use dialoguer::Select;
-use rand::rngs::OsRng;
+use dialoguer::{Confirm, MultiSelect, Select};
 use rocket::data::{Data, ToByteUnit};
 use rocket::http::{ContentType, Status};
 use rocket::request::{FromRequest, Outcome};
 use rocket::{post, routes, Config, Request, State};
-use rsa::{
-    pkcs8::DecodePublicKey, traits::PaddingScheme, Pkcs1v15Encrypt, RsaPrivateKey, RsaPublicKey,
-};
+use rsa::{traits::PaddingScheme, RsaPrivateKey};
+use std::collections::HashMap;
 use std::fs::{self, File};
-use std::io::{Read, Write};
+use std::io::{BufReader, BufWriter, IsTerminal, Write};
 use std::path::{Path, PathBuf};
-use std::sync::Arc;
-use tfhe::integer::{BooleanBlock, RadixCiphertext, RadixClientKey};
+use std::sync::{Arc, Mutex};
+use tfhe::integer::{
+    BooleanBlock, IntegerCiphertext, IntegerRadixCiphertext, RadixCiphertext, RadixClientKey,
+    SignedRadixCiphertext,
+};
+
+use crate::container::{Encoding, EncryptedColumn, EncryptedFields, FheDataset};
+use crate::envelope::{KeyEnvelope, PublicKey, Role};
+use crate::fhe_keys::{load_client_key, load_server_key_bytes, save_key_pair};
+use crate::file_cipher;
 
-use aes::cipher::{block_padding::Pkcs7, BlockDecryptMut, BlockEncryptMut, KeyIvInit};
-use aes::Aes256;
-use base64::encode;
+use base64::engine::general_purpose::STANDARD as BASE64_STANDARD;
+use base64::write::EncoderWriter;
+use base64::Engine;
 use csv::ReaderBuilder;
 use rand::Rng;
 
-type Aes256Cbc = cbc::Encryptor<aes::Aes256>;
 use tfhe::prelude::*;
 use tfhe::shortint::parameters::{PARAM_MESSAGE_2_CARRY_3_KS_PBS, PARAM_MESSAGE_2_CARRY_6_KS_PBS};
-use tfhe::{
-    generate_keys,
-    integer::{gen_keys_radix, ServerKey},
-    set_server_key, ConfigBuilder, FheUint64,
-};
+use tfhe::{generate_keys, integer::gen_keys_radix, set_server_key, ConfigBuilder, FheUint64};
 use tokio::io::AsyncReadExt;
 
+/// Parameter set and radix width used for FHE datasets, recorded in their header.
+const FHE_PARAM_SET: &str = "PARAM_MESSAGE_2_CARRY_3_KS_PBS";
+/// Plaintext bits per radix block of `FHE_PARAM_SET`.
+const FHE_BITS_PER_BLOCK: usize = 2;
+/// Written by `key-gen`.
+const DEFAULT_OWNER_KEY: &str = "keys/public_key.pem";
+
 #[derive(Debug, Clone, Parser)]
 pub struct StoreCmd {
     #[arg(short, long)]
@@ -39,10 +46,124 @@ pub struct StoreCmd {
 
     #[arg(short, long)]
     output: String,
+
+    /// Encryption type, prompted for when missing
+    #[arg(short, long, value_enum)]
+    mode: Option<EncryptionType>,
+
+    /// CSV column to encrypt with FHE, repeat for several; prompted for when missing
+    #[arg(short, long)]
+    column: Vec<String>,
+
+    /// Never prompt; assume yes for confirmations and fail on missing flags
+    #[arg(short, long)]
+    yes: bool,
+
+    /// Start the client-side decrypt server after an FHE run, which
+    /// interactive runs always do; headless runs exit without it
+    #[arg(long)]
+    serve: bool,
+
+    /// Reuse a previously generated FHE key instead of creating a new one
+    #[arg(long)]
+    fhe_key: Option<String>,
+
+    /// Bits per encrypted FHE value; sums wrap around past this width
+    #[arg(long, value_enum, default_value_t = FheWidth::Bits16)]
+    fhe_bits: FheWidth,
+
+    /// Decimals kept when encrypting FHE columns as fixed-point numbers,
+    /// taken from the data when missing
+    #[arg(long)]
+    scale: Option<u32>,
+
+    /// Largest absolute value in the FHE columns, in their units; sums are
+    /// sized by it instead of the whole width
+    #[arg(long)]
+    max_value: Option<String>,
+
+    /// Data owner's public key, the AES key is always wrapped for it
+    #[arg(long, default_value = DEFAULT_OWNER_KEY)]
+    owner_key: PathBuf,
+
+    /// Extra public key (e.g. an auditor) to wrap the AES key for, repeatable
+    #[arg(long)]
+    recipient: Vec<PathBuf>,
+
+    /// Leave the node out of the key envelope; buyers are given access with
+    /// grant-access instead. Needs a secp256k1 owner key
+    #[arg(long)]
+    proxy_reencryption: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum EncryptionType {
+    /// Fully homomorphic encryption of integer CSV columns
+    Fhe,
+    /// AES symmetric key dual encryption of the whole file
+    Aes,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum FheWidth {
+    #[value(name = "16")]
+    Bits16,
+    #[value(name = "32")]
+    Bits32,
+    #[value(name = "64")]
+    Bits64,
+}
+
+impl FheWidth {
+    fn bits(self) -> u32 {
+        match self {
+            FheWidth::Bits16 => 16,
+            FheWidth::Bits32 => 32,
+            FheWidth::Bits64 => 64,
+        }
+    }
+
+    fn num_blocks(self) -> usize {
+        self.bits() as usize / FHE_BITS_PER_BLOCK
+    }
 }
 
 struct ComputeTypeHeader(String);
 
+/// Encoding of the values a job was computed on, from the `signed` and
+/// `scale` headers; plain unsigned integers when they are missing.
+struct EncodingHeader(Encoding);
+
+struct KeyIdHeader(String);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for KeyIdHeader {
+    type Error = std::io::Error;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        match request.headers().get_one("key_id") {
+            Some(header) => Outcome::Success(KeyIdHeader(header.to_string())),
+            None => Outcome::Forward(Status::BadRequest),
+        }
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for EncodingHeader {
+    type Error = std::io::Error;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let headers = request.headers();
+        Outcome::Success(EncodingHeader(Encoding {
+            signed: headers.get_one("signed") == Some("true"),
+            scale: headers
+                .get_one("scale")
+                .and_then(|scale| scale.parse().ok())
+                .unwrap_or(0),
+        }))
+    }
+}
+
 #[rocket::async_trait]
 impl<'r> FromRequest<'r> for ComputeTypeHeader {
     type Error = std::io::Error;
@@ -62,35 +183,51 @@ impl<'r> FromRequest<'r> for ComputeTypeHeader {
     }
 }
 
-fn encrypt_file(file_path: PathBuf, key: &[u8], iv: &[u8]) -> Vec<u8> {
-    let mut data = std::fs::read(file_path.clone()).expect("Unable to read file");
-    let cipher = Aes256Cbc::new_from_slices(key, iv).unwrap();
-    let mut buffer = vec![0u8; data.len() + Aes256::block_size()];
-    buffer[..data.len()].copy_from_slice(&data);
-
-    let ciphertext = cipher
-        .encrypt_padded_mut::<Pkcs7>(&mut buffer, data.len())
-        .unwrap();
-
+/// Encrypts `file_path` into a base64 file at `output`, a chunk at a time so
+/// memory use does not grow with the input.
+fn encrypt_file(
+    file_path: &Path,
+    output: &Path,
+    key: &[u8],
+) -> Result<(), Box<dyn std::error::Error>> {
     let file_extension = file_path.extension().unwrap().to_str().unwrap();
-    let metadata = format!("{}:{}", file_extension.len(), file_extension);
-
-    [
-        iv.to_vec(),
-        metadata.as_bytes().to_vec(),
-        ciphertext.to_vec(),
-    ]
-    .concat()
+    let input = BufReader::new(File::open(file_path)?);
+    fs::create_dir_all(output.parent().expect("No parent directory found"))?;
+    let mut writer = EncoderWriter::new(BufWriter::new(File::create(output)?), &BASE64_STANDARD);
+    file_cipher::encrypt(input, &mut writer, key, file_extension)?;
+    writer.finish()?.flush()?;
+    Ok(())
 }
 
-fn encrypt_symmetric_key(symmetric_key: &[u8], public_keys: &[RsaPublicKey]) -> Vec<u8> {
-    public_keys[1]
-        .encrypt(&mut OsRng, Pkcs1v15Encrypt, symmetric_key)
-        .expect("failed to encrypt")
+fn read_public_key(path: &Path) -> Result<PublicKey, Box<dyn std::error::Error>> {
+    let pem = std::fs::read_to_string(path)
+        .map_err(|err| format!("Unable to read public key {}: {}", path.display(), err))?;
+    Ok(PublicKey::from_pem(&pem)
+        .map_err(|err| format!("Failed to parse public key {}: {}", path.display(), err))?)
+}
+
+/// Public key of the local zen-node, which the AES key is wrapped for so the
+/// node can compute on and serve the dataset.
+async fn fetch_node_key() -> Result<PublicKey, Box<dyn std::error::Error>> {
+    let response = reqwest::get("http://localhost:8000/pubkey")
+        .await?
+        .json::<serde_json::Value>()
+        .await?;
+    let parsed_server_key_string = response
+        .get("pubkey")
+        .and_then(|value| Some(value.to_string()))
+        .unwrap();
+    let parsed_server_key = parsed_server_key_string
+        .trim()
+        .replace("\\n", "\n")
+        .trim_matches('"')
+        .to_string();
+    Ok(PublicKey::from_pem(parsed_server_key.as_str())
+        .map_err(|err| format!("Failed to parse server public key: {}", err))?)
 }
 
 fn save_base64_to_file(filename: &str, data: &[u8]) {
-    let base64_data = encode(data);
+    let base64_data = BASE64_STANDARD.encode(data);
     let dir = Path::new(filename)
         .parent()
         .expect("No parent directory found");
@@ -113,7 +250,10 @@ fn read_csv_headers(file_path: &str) -> Result<Vec<String>, Box<dyn std::error::
     Ok(headers_vec)
 }
 
-fn read_csv_column(file_path: &str, column: &str) -> Result<Vec<u64>, Box<dyn std::error::Error>> {
+fn read_csv_column(
+    file_path: &str,
+    column: &str,
+) -> Result<Vec<String>, Box<dyn std::error::Error>> {
     let mut rdr = ReaderBuilder::new()
         .has_headers(true)
         .from_path(file_path)?;
@@ -128,65 +268,407 @@ fn read_csv_column(file_path: &str, column: &str) -> Result<Vec<u64>, Box<dyn st
 
     for result in rdr.records() {
         let record = result?;
-        if let Some(value) = record.get(column_index) {
-            if let Ok(parsed_value) = value.parse::<u64>() {
-                column_data.push(parsed_value);
-            } else {
-                eprintln!("Warning: Skipping invalid value '{}'", value);
-            }
-        }
+        column_data.push(record.get(column_index).unwrap_or_default().to_string());
     }
 
     Ok(column_data)
 }
 
-struct KeyPair {
-    client_key: RadixClientKey,
-    server_key: ServerKey,
+/// Number of decimals written in `value`.
+fn decimals(value: &str) -> u32 {
+    value
+        .trim()
+        .split_once('.')
+        .map_or(0, |(_, fraction)| fraction.len() as u32)
+}
+
+/// `value` times `10^scale`, exactly, or `None` when it is not a number with
+/// at most `scale` decimals.
+fn parse_fixed(value: &str, scale: u32) -> Option<i128> {
+    let value = value.trim();
+    let (negative, digits) = match value.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, value.strip_prefix('+').unwrap_or(value)),
+    };
+    let (whole, fraction) = digits.split_once('.').unwrap_or((digits, ""));
+    if (whole.is_empty() && fraction.is_empty())
+        || fraction.len() > scale as usize
+        || !whole
+            .chars()
+            .chain(fraction.chars())
+            .all(|c| c.is_ascii_digit())
+    {
+        return None;
+    }
+    let digits = format!("{}{:0<width$}", whole, fraction, width = scale as usize);
+    let digits = digits.trim_start_matches('0');
+    let magnitude: i128 = if digits.is_empty() {
+        0
+    } else {
+        digits.parse().ok()?
+    };
+    Some(if negative { -magnitude } else { magnitude })
+}
+
+/// Turns the CSV values of a column into the integers to encrypt: scaled to
+/// `scale` decimals (as many as the data has when `None`) and signed when any
+/// is negative. Fails on values that are not numbers or do not fit `width`.
+fn encode_column(
+    name: &str,
+    values: &[String],
+    scale: Option<u32>,
+    width: FheWidth,
+) -> Result<(Vec<i128>, Encoding), String> {
+    let scale = scale.unwrap_or_else(|| {
+        values
+            .iter()
+            .map(|value| decimals(value))
+            .max()
+            .unwrap_or(0)
+    });
+    let values = values
+        .iter()
+        .enumerate()
+        .map(|(row, value)| {
+            parse_fixed(value, scale).ok_or_else(|| {
+                format!(
+                    "Invalid value '{}' in column {} row {}, expected a number with at most {} decimals",
+                    value,
+                    name,
+                    row + 1,
+                    scale
+                )
+            })
+        })
+        .collect::<Result<Vec<i128>, String>>()?;
+    let signed = values.iter().any(|value| *value < 0);
+    let bits = width.bits();
+    let (min, max) = if signed {
+        (-(1i128 << (bits - 1)), (1i128 << (bits - 1)) - 1)
+    } else {
+        (0, (1i128 << bits) - 1)
+    };
+    if let Some(value) = values.iter().find(|value| **value < min || **value > max) {
+        return Err(format!(
+            "Column {} holds {} (scale {}), which does not fit {} bit{}; use a larger --fhe-bits",
+            name,
+            value,
+            scale,
+            bits,
+            if signed { " signed" } else { "" }
+        ));
+    }
+    Ok((values, Encoding { signed, scale }))
+}
+
+/// Encodes `max_value` like the values of a column and checks it bounds every
+/// one of them.
+fn declared_max(
+    name: &str,
+    values: &[i128],
+    encoding: Encoding,
+    max_value: &str,
+) -> Result<u64, String> {
+    let max = parse_fixed(max_value, encoding.scale)
+        .filter(|max| *max >= 0 && *max <= u64::MAX as i128)
+        .ok_or_else(|| {
+            format!(
+                "Invalid --max-value {}, expected a positive number with at most {} decimals",
+                max_value, encoding.scale
+            )
+        })?;
+    if let Some(value) = values.iter().find(|value| value.abs() > max) {
+        return Err(format!(
+            "Column {} holds {} (scale {}), more than --max-value {}",
+            name, value, encoding.scale, max_value
+        ));
+    }
+    Ok(max as u64)
+}
+
+/// Decrypts `value`, two's complement when `signed`.
+/// Reads a node's result, refusing bodies that do not hold what `compute_type` promised.
+fn deserialize_result<T: serde::de::DeserializeOwned>(
+    buffer: &[u8],
+) -> Result<T, (Status, String)> {
+    bincode::deserialize_from(buffer).map_err(|err| {
+        log::warn!("Unable to read the computed result: {}", err);
+        (
+            Status::BadRequest,
+            format!("Body is not a valid result for this compute type: {}", err),
+        )
+    })
+}
+
+fn decrypt_integer(client_key: &RadixClientKey, value: &RadixCiphertext, signed: bool) -> i128 {
+    if signed {
+        client_key.decrypt_signed(&SignedRadixCiphertext::from_blocks(value.blocks().to_vec()))
+    } else {
+        client_key.decrypt::<u128>(value) as i128
+    }
+}
+
+fn decrypt_fields(client_key: &RadixClientKey, data: &EncryptedFields) -> Vec<(String, i128)> {
+    data.fields
+        .iter()
+        .map(|(name, value)| {
+            (
+                name.clone(),
+                decrypt_integer(client_key, value, data.signed),
+            )
+        })
+        .collect()
+}
+
+/// `value` with its last `scale` digits as decimals.
+fn format_fixed(value: i128, scale: u32) -> String {
+    if scale == 0 {
+        return value.to_string();
+    }
+    let unit = 10u128.pow(scale);
+    format!(
+        "{}{}.{:0width$}",
+        if value < 0 { "-" } else { "" },
+        value.unsigned_abs() / unit,
+        value.unsigned_abs() % unit,
+        width = scale as usize
+    )
+}
+
+fn field(fields: &[(String, i128)], name: &str) -> i128 {
+    fields
+        .iter()
+        .find(|(field, _)| field == name)
+        .map_or(0, |(_, value)| *value)
+}
+
+/// Result of an `Average`, `Min` or `Max` job, which means nothing when the
+/// filter matched no rows.
+fn counted_result(fields: &[(String, i128)], scale: u32) -> String {
+    if field(fields, "count") == 0 {
+        return "no matching rows".to_string();
+    }
+    format_fixed(field(fields, "result"), scale)
+}
+
+/// Population variance and standard deviation from the decrypted sums of a
+/// `Variance` job, in the units of the column.
+fn variance_json(fields: &[(String, i128)], scale: u32) -> serde_json::Value {
+    let count = field(fields, "count");
+    if count == 0 {
+        return serde_json::json!({ "count": 0, "mean": null, "variance": null, "stddev": null });
+    }
+    let unit = 10f64.powi(scale as i32);
+    let mean = field(fields, "sum") as f64 / unit / count as f64;
+    let variance =
+        (field(fields, "sum_squares") as f64 / (unit * unit) / count as f64 - mean * mean).max(0.0);
+    serde_json::json!({
+        "count": count as u64,
+        "mean": mean,
+        "variance": variance,
+        "stddev": variance.sqrt(),
+    })
+}
+
+/// Buckets of a `Histogram` job in the order they were requested.
+fn histogram_json(fields: &[(String, i128)]) -> serde_json::Value {
+    let buckets: Vec<serde_json::Value> = fields
+        .iter()
+        .map(|(range, count)| serde_json::json!({ "range": range, "count": *count as u64 }))
+        .collect();
+    serde_json::json!({ "buckets": buckets })
+}
+
+/// Client keys the decrypt server can answer with, loaded from `keys/fhe/` on
+/// first use. Jobs without a `key_id` header use the key of this run.
+struct Keyring {
+    current: String,
+    client_keys: Mutex<HashMap<String, Arc<RadixClientKey>>>,
+}
+
+impl Keyring {
+    fn client_key(&self, key_id: Option<&str>) -> Result<Arc<RadixClientKey>, std::io::Error> {
+        let key_id = key_id.unwrap_or(&self.current);
+        let mut client_keys = self.client_keys.lock().unwrap();
+        if let Some(client_key) = client_keys.get(key_id) {
+            return Ok(client_key.clone());
+        }
+        let client_key = Arc::new(load_client_key(key_id)?);
+        client_keys.insert(key_id.to_string(), client_key.clone());
+        Ok(client_key)
+    }
 }
 impl StoreCmd {
+    /// Prompts are only shown when a human can answer them.
+    fn interactive(&self) -> bool {
+        !self.yes && std::io::stdin().is_terminal()
+    }
+
+    fn encryption_type(&self) -> Result<EncryptionType, Box<dyn std::error::Error>> {
+        if let Some(mode) = self.mode {
+            return Ok(mode);
+        }
+        if !self.interactive() {
+            return Err("Missing --mode <fhe|aes> and unable to prompt for it".into());
+        }
+        let enc_type = Select::with_theme(&ColorfulTheme::default())
+            .with_prompt("Select Encryption Type")
+            .items(&["FHE Encryption", "AES Symmetric Key Dual Encryption"])
+            .interact()?;
+        Ok([EncryptionType::Fhe, EncryptionType::Aes][enc_type])
+    }
+
+    fn selected_columns(
+        &self,
+        headers: &[String],
+    ) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+        if self.column.is_empty() {
+            if !self.interactive() {
+                return Err("Missing --column <name> and unable to prompt for it".into());
+            }
+            let selected_headers = MultiSelect::with_theme(&ColorfulTheme::default())
+                .with_prompt(
+                    "Select Headers [Int Values] to Encrypt, Computation can only be performed on these",
+                )
+                .items(headers)
+                .interact()?;
+            if selected_headers.is_empty() {
+                return Err("No column selected".into());
+            }
+            return Ok(selected_headers
+                .into_iter()
+                .map(|index| headers[index].clone())
+                .collect());
+        }
+        for column in &self.column {
+            if !headers.contains(column) {
+                return Err(
+                    format!("Column '{}' not found, available: {:?}", column, headers).into(),
+                );
+            }
+        }
+        Ok(self.column.clone())
+    }
+
+    /// Asks before overwriting a previously processed dataset.
+    fn confirm_overwrite(&self, dataset_dir: &Path) -> Result<(), Box<dyn std::error::Error>> {
+        if !dataset_dir.exists() || self.yes {
+            return Ok(());
+        }
+        if !self.interactive() {
+            return Err(format!(
+                "{} already exists, pass --yes to overwrite it",
+                dataset_dir.display()
+            )
+            .into());
+        }
+        let overwrite = Confirm::with_theme(&ColorfulTheme::default())
+            .with_prompt(format!(
+                "{} already exists, overwrite?",
+                dataset_dir.display()
+            ))
+            .default(false)
+            .interact()?;
+        if overwrite {
+            Ok(())
+        } else {
+            Err("Aborted".into())
+        }
+    }
+
     pub async fn execute(&self) -> Result<(), Box<dyn std::error::Error>> {
         log::info!("Data Processing, Input Path: {}", &self.input);
         let input_path = PathBuf::from(&self.input);
         let output_path = PathBuf::from(&self.output);
         let input_extension = input_path.extension().unwrap().to_str().unwrap();
 
-        let enc_type = Select::with_theme(&ColorfulTheme::default())
-            .with_prompt("Select Encryption Type")
-            .items(&["FHE Encryption", "AES Symmetric Key Dual Encryption"])
-            .interact()
-            .unwrap();
+        let enc_type = self.encryption_type()?;
+        self.confirm_overwrite(&output_path.join(input_path.file_stem().unwrap()))?;
 
         match enc_type {
-            0 => {
+            EncryptionType::Fhe => {
                 // check if csv, ask which row to encrypt,
                 if input_extension != "csv" {
                     return Err("Only csv input allowed for FHE Enc".into());
                 }
                 let headers = read_csv_headers(input_path.to_str().unwrap())?;
 
-                let selected_header = Select::with_theme(&ColorfulTheme::default())
-                    .with_prompt(
-                        "Select Header [Int Values] to Encrypt, Computation can only be performed on these",
-                    )
-                    .items(&headers)
-                    .interact()
-                    .unwrap();
-                let selected_column = &headers[selected_header];
-                let column_data = read_csv_column(input_path.to_str().unwrap(), &selected_column)?;
+                let selected_columns = self.selected_columns(&headers)?;
                 log::info!("Encrypting data using Fully homomorphic encryption. Hold On Might Take a Minute!!");
                 // generate client and server keys
-                let (client_key, server_key) = gen_keys_radix(PARAM_MESSAGE_2_CARRY_3_KS_PBS, 8);
-                let key_pair = Arc::new(KeyPair {
-                    client_key: client_key.clone(),
-                    server_key: server_key.clone(),
-                });
-                let mut serialized_data = Vec::new();
-                bincode::serialize_into(&mut serialized_data, &server_key)?;
-                for data in column_data {
-                    let value = client_key.encrypt(data);
-                    bincode::serialize_into(&mut serialized_data, &value);
+                let (key_id, client_key) = match &self.fhe_key {
+                    Some(key_id) => {
+                        log::info!("Reusing FHE key {}", key_id);
+                        (key_id.clone(), load_client_key(key_id)?)
+                    }
+                    None => {
+                        let (client_key, server_key) = gen_keys_radix(
+                            PARAM_MESSAGE_2_CARRY_3_KS_PBS,
+                            self.fhe_bits.num_blocks(),
+                        );
+                        let key_id = save_key_pair(&client_key, &server_key)?;
+                        log::info!(
+                            "Generated FHE key {}, saved to ./keys/fhe/{}",
+                            key_id,
+                            key_id
+                        );
+                        (key_id, client_key)
+                    }
+                };
+                let num_blocks = self.fhe_bits.num_blocks();
+                let mut columns = Vec::new();
+                for selected_column in selected_columns {
+                    let column_data =
+                        read_csv_column(input_path.to_str().unwrap(), &selected_column)?;
+                    let (column_data, encoding) =
+                        encode_column(&selected_column, &column_data, self.scale, self.fhe_bits)?;
+                    let max = match &self.max_value {
+                        Some(max_value) => Some(declared_max(
+                            &selected_column,
+                            &column_data,
+                            encoding,
+                            max_value,
+                        )?),
+                        None => None,
+                    };
+                    log::info!(
+                        "Encrypting column {} ({} rows, {} bit{}, {} decimals)",
+                        selected_column,
+                        column_data.len(),
+                        self.fhe_bits.bits(),
+                        if encoding.signed { " signed" } else { "" },
+                        encoding.scale
+                    );
+                    // The client key encrypts at any width, whatever it was generated for
+                    let client_key = client_key.as_ref();
+                    columns.push(EncryptedColumn {
+                        name: selected_column,
+                        values: column_data
+                            .into_iter()
+                            .map(|data| {
+                                if encoding.signed {
+                                    RadixCiphertext::from_blocks(
+                                        client_key
+                                            .encrypt_signed_radix(data as i64, num_blocks)
+                                            .into_blocks(),
+                                    )
+                                } else {
+                                    client_key.encrypt_radix(data as u64, num_blocks)
+                                }
+                            })
+                            .collect(),
+                        encoding,
+                        max,
+                    });
                 }
+                let serialized_data = FheDataset {
+                    param_set: FHE_PARAM_SET.to_string(),
+                    num_blocks,
+                    key_id: Some(key_id.clone()),
+                    server_key: None,
+                    columns,
+                }
+                .to_bytes()?;
                 save_base64_to_file(
                     format!(
                         "{}/{}/fhe_enc_data.b64",
@@ -196,33 +678,90 @@ impl StoreCmd {
                     .as_str(),
                     &serialized_data,
                 );
+                // Uploaded once per owner, every dataset under this key refers to it
+                save_base64_to_file(
+                    format!(
+                        "{}/{}/fhe_server_key.b64",
+                        output_path.to_str().unwrap(),
+                        input_path.file_stem().unwrap().to_str().unwrap()
+                    )
+                    .as_str(),
+                    &load_server_key_bytes(&key_id)?,
+                );
+                if !self.serve && !self.interactive() {
+                    log::info!(
+                        "Data successfully Processed!! Pass --serve to start the decrypt server"
+                    );
+                    return Ok(());
+                }
+                let keyring = Arc::new(Keyring {
+                    current: key_id.clone(),
+                    client_keys: Mutex::new(HashMap::from([(key_id, Arc::new(client_key))])),
+                });
 
                 #[post("/process_job", format = "application/octet-stream", data = "<data>")]
                 async fn process_job<'a>(
                     content_type: &ContentType,
                     data: Data<'_>,
-                    key_pair: &State<Arc<KeyPair>>,
+                    keyring: &State<Arc<Keyring>>,
                     compute_type: ComputeTypeHeader,
-                ) -> Result<String, std::io::Error> {
+                    key_id: Option<KeyIdHeader>,
+                    encoding: EncodingHeader,
+                ) -> Result<String, (Status, String)> {
+                    let encoding = encoding.0;
                     let mut buffer = Vec::new();
-                    data.open(400.mebibytes()).read_to_end(&mut buffer).await?;
+                    data.open(400.mebibytes())
+                        .read_to_end(&mut buffer)
+                        .await
+                        .map_err(|err| (Status::BadRequest, err.to_string()))?;
                     let computetype = compute_type.0.as_str();
+                    let client_key = keyring
+                        .client_key(key_id.as_ref().map(|h| h.0.as_str()))
+                        .map_err(|err| match err.kind() {
+                            // Malformed or unknown key_id header
+                            std::io::ErrorKind::InvalidData | std::io::ErrorKind::NotFound => {
+                                (Status::BadRequest, err.to_string())
+                            }
+                            _ => (Status::InternalServerError, err.to_string()),
+                        })?;
 
                     let output: String = match computetype {
-                        "Average" | "Total" => {
-                            let data: RadixCiphertext =
-                                bincode::deserialize_from(&buffer[..]).unwrap();
-                            let client_key = &key_pair.client_key;
+                        "Total" => {
+                            let data: RadixCiphertext = deserialize_result(&buffer)?;
+                            let res = decrypt_integer(&client_key, &data, encoding.signed);
+                            format_fixed(res, encoding.scale)
+                        }
+                        "Average" | "Min" | "Max" => {
+                            let data: EncryptedFields = deserialize_result(&buffer)?;
+                            counted_result(&decrypt_fields(&client_key, &data), encoding.scale)
+                        }
+                        "Count" | "CountMatching" => {
+                            let data: RadixCiphertext = deserialize_result(&buffer)?;
                             let res: u64 = client_key.decrypt(&data);
                             format!("{}", res)
                         }
-                        "GT" | "LT" | "GE" | "LE" => {
-                            let data: BooleanBlock =
-                                bincode::deserialize_from(&buffer[..]).unwrap();
-                            let client_key = &key_pair.client_key;
+                        "CompareTotal" => {
+                            let data: BooleanBlock = deserialize_result(&buffer)?;
                             let res: bool = client_key.decrypt_bool(&data);
                             format!("{}", res)
                         }
+                        "CompareRows" => {
+                            let data: Vec<BooleanBlock> = deserialize_result(&buffer)?;
+                            let res: Vec<bool> = data
+                                .iter()
+                                .map(|value| client_key.decrypt_bool(value))
+                                .collect();
+                            serde_json::to_string(&res).unwrap()
+                        }
+                        "Variance" | "Histogram" => {
+                            let data: EncryptedFields = deserialize_result(&buffer)?;
+                            let fields = decrypt_fields(&client_key, &data);
+                            if computetype == "Variance" {
+                                variance_json(&fields, encoding.scale).to_string()
+                            } else {
+                                histogram_json(&fields).to_string()
+                            }
+                        }
 
                         _ => ("Invalid Compute type").to_string(),
                     };
@@ -237,47 +776,54 @@ impl StoreCmd {
                 log::info!("Starting a client-side decrypt server on http://localhost:6000/ ");
                 rocket::custom(&config)
                     .mount("/", routes![process_job])
-                    .manage(key_pair)
+                    .manage(keyring)
                     .launch()
                     .await?;
             }
-            1 => {
-                log::info!("Encrypting data using Dual Aes encryption. Hold On Might Take a Minute!!");
+            EncryptionType::Aes => {
+                if !self.column.is_empty() {
+                    log::warn!(
+                        "--column is ignored for AES encryption, the whole file is encrypted"
+                    );
+                }
+                log::info!(
+                    "Encrypting data using Dual Aes encryption. Hold On Might Take a Minute!!"
+                );
 
                 let symmetric_key: [u8; 32] = rand::thread_rng().gen();
-                let iv: [u8; 16] = rand::thread_rng().gen();
-                let encrypted_data = encrypt_file(input_path.clone(), &symmetric_key, &iv);
-                save_base64_to_file(
-                    format!(
+                encrypt_file(
+                    &input_path,
+                    Path::new(&format!(
                         "{}/{}/enc_data.b64",
                         output_path.to_str().unwrap(),
                         input_path.file_stem().unwrap().to_str().unwrap()
-                    )
-                    .as_str(),
-                    &encrypted_data,
-                );
-
-                let pub_ke_filey = std::fs::read_to_string("./keys/key1/public_key1.pem").unwrap();
-                let client_pub_key = RsaPublicKey::from_public_key_pem(&pub_ke_filey).unwrap();
-                let response = reqwest::get("http://localhost:8000/pubkey")
-                    .await?
-                    .json::<serde_json::Value>()
-                    .await?;
-                let parsed_server_key_string = response
-                    .get("pubkey")
-                    .and_then(|value| Some(value.to_string()))
-                    .unwrap();
-                let parsed_server_key = parsed_server_key_string
-                    .trim()
-                    .replace("\\n", "\n")
-                    .trim_matches('"')
-                    .to_string();
-                let server_pub_key = RsaPublicKey::from_public_key_pem(parsed_server_key.as_str())
-                    .map_err(|err| format!("Failed to parse server public key: {}", err))?;
-                let enc_sym_key = encrypt_symmetric_key(
+                    )),
                     &symmetric_key,
-                    &vec![client_pub_key.clone(), server_pub_key],
-                );
+                )?;
+
+                let client_pub_key = read_public_key(&self.owner_key)?;
+                let mut recipients = if self.proxy_reencryption {
+                    if !matches!(client_pub_key, PublicKey::Secp256k1(_)) {
+                        return Err(
+                            "--proxy-reencryption needs a secp256k1 owner key (key-gen -a secp256k1)"
+                                .into(),
+                        );
+                    }
+                    vec![(Role::Owner, client_pub_key)]
+                } else {
+                    vec![
+                        (Role::Owner, client_pub_key),
+                        (Role::Node, fetch_node_key().await?),
+                    ]
+                };
+                for path in &self.recipient {
+                    recipients.push((Role::Auditor, read_public_key(path)?));
+                }
+                let envelope = KeyEnvelope::seal(&symmetric_key, &recipients)?;
+                for slot in &envelope.recipients {
+                    log::info!("Wrapped AES key for {:?} {}", slot.role, slot.fingerprint);
+                }
+                let enc_sym_key = envelope.to_bytes();
                 save_base64_to_file(
                     format!(
                         "{}/{}/enc_sym_keys.b64",
@@ -289,12 +835,147 @@ impl StoreCmd {
                 );
                 log::info!("Data successfully Processed!!");
             }
-            _ => {
-                eprintln!("Invalid Input");
-                return Err("Invalid Input".into());
-            }
         }
 
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strings(values: &[&str]) -> Vec<String> {
+        values.iter().map(|value| value.to_string()).collect()
+    }
+
+    #[test]
+    fn parse_fixed_scales_exactly() {
+        assert_eq!(parse_fixed("1.5", 2), Some(150));
+        assert_eq!(parse_fixed("1.25", 2), Some(125));
+        assert_eq!(parse_fixed("42", 3), Some(42_000));
+        assert_eq!(parse_fixed(" +7.0 ", 1), Some(70));
+        assert_eq!(parse_fixed(".5", 1), Some(5));
+        assert_eq!(parse_fixed("0.00", 2), Some(0));
+    }
+
+    #[test]
+    fn parse_fixed_refuses_to_round() {
+        assert_eq!(parse_fixed("1.25", 1), None);
+        assert_eq!(parse_fixed("0.001", 2), None);
+        assert_eq!(parse_fixed("3.0", 0), None);
+    }
+
+    #[test]
+    fn parse_fixed_handles_negatives() {
+        assert_eq!(parse_fixed("-1.5", 2), Some(-150));
+        assert_eq!(parse_fixed("-0.25", 2), Some(-25));
+        assert_eq!(parse_fixed("-0", 0), Some(0));
+    }
+
+    #[test]
+    fn parse_fixed_rejects_non_numbers() {
+        for value in ["", "-", ".", "abc", "1e3", "1.2.3", "--1", "1,5", "0x10"] {
+            assert_eq!(parse_fixed(value, 2), None, "{:?}", value);
+        }
+        // More digits than an i128 holds
+        assert_eq!(parse_fixed(&"9".repeat(40), 0), None);
+    }
+
+    #[test]
+    fn encode_column_infers_the_scale() {
+        let (values, encoding) = encode_column(
+            "temp",
+            &strings(&["1.5", "2.25", "10"]),
+            None,
+            FheWidth::Bits32,
+        )
+        .unwrap();
+        assert_eq!(values, vec![150, 225, 1000]);
+        assert_eq!(
+            encoding,
+            Encoding {
+                signed: false,
+                scale: 2
+            }
+        );
+    }
+
+    #[test]
+    fn encode_column_refuses_more_decimals_than_the_scale() {
+        let err = encode_column(
+            "temp",
+            &strings(&["1.5", "2.25"]),
+            Some(1),
+            FheWidth::Bits32,
+        )
+        .unwrap_err();
+        assert!(err.contains("row 2"), "{}", err);
+    }
+
+    #[test]
+    fn encode_column_signs_columns_with_negatives() {
+        let (values, encoding) =
+            encode_column("temp", &strings(&["-1.5", "3"]), None, FheWidth::Bits16).unwrap();
+        assert_eq!(values, vec![-15, 30]);
+        assert!(encoding.signed);
+        assert_eq!(encoding.scale, 1);
+    }
+
+    #[test]
+    fn encode_column_checks_the_width() {
+        assert!(encode_column("n", &strings(&["65535"]), None, FheWidth::Bits16).is_ok());
+        assert!(encode_column("n", &strings(&["65536"]), None, FheWidth::Bits16).is_err());
+        // Scaling can push a value out of range
+        assert!(encode_column("n", &strings(&["655.36"]), Some(2), FheWidth::Bits16).is_err());
+        // A negative value halves the positive range
+        assert!(encode_column("n", &strings(&["-32768", "32767"]), None, FheWidth::Bits16).is_ok());
+        assert!(encode_column("n", &strings(&["-1", "32768"]), None, FheWidth::Bits16).is_err());
+        assert!(encode_column("n", &strings(&["-32769"]), None, FheWidth::Bits16).is_err());
+        assert!(encode_column(
+            "n",
+            &strings(&["18446744073709551615"]),
+            None,
+            FheWidth::Bits64
+        )
+        .is_ok());
+    }
+
+    #[test]
+    fn encode_column_rejects_non_numbers() {
+        let err = encode_column("n", &strings(&["1", "n/a"]), None, FheWidth::Bits32).unwrap_err();
+        assert!(err.contains("'n/a'"), "{}", err);
+    }
+
+    #[test]
+    fn counted_result_reports_empty_selections() {
+        let fields = |count: i128, result: i128| {
+            vec![("count".to_string(), count), ("result".to_string(), result)]
+        };
+        assert_eq!(
+            counted_result(&fields(0, u32::MAX as i128), 0),
+            "no matching rows"
+        );
+        assert_eq!(counted_result(&fields(2, 613), 2), "6.13");
+    }
+
+    #[test]
+    fn format_fixed_round_trips() {
+        assert_eq!(format_fixed(150, 2), "1.50");
+        assert_eq!(format_fixed(-25, 2), "-0.25");
+        assert_eq!(format_fixed(42, 0), "42");
+        assert_eq!(format_fixed(parse_fixed("-7.75", 2).unwrap(), 2), "-7.75");
+    }
+
+    #[test]
+    fn refuses_results_that_do_not_match_the_compute_type() {
+        let Err((status, _)) = deserialize_result::<EncryptedFields>(b"not a result") else {
+            panic!("garbage was read as fields");
+        };
+        assert_eq!(status, Status::BadRequest);
+        let Err((status, _)) = deserialize_result::<Vec<BooleanBlock>>(&[]) else {
+            panic!("an empty body was read as rows");
+        };
+        assert_eq!(status, Status::BadRequest);
+    }
+}
@@ -0,0 +1,317 @@
+use crate::catalog::{hash_file, Catalog, CompanionBackup, DatasetRecord, ENVELOPE_FILE};
+use crate::fhe_keys;
+use crate::storage::{download, other, StorageBackend};
+use clap::Parser;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+#[derive(Debug, Clone, Parser)]
+pub struct RestoreCmd {
+    /// Only restore datasets of this owner address
+    #[arg(long)]
+    owner: Option<String>,
+
+    /// Only restore this dataset (file id), usually together with --owner
+    #[arg(long)]
+    dataset: Option<String>,
+
+    /// Fetch through this IPFS gateway (e.g. https://ipfs.io) instead of the storage backend
+    #[arg(long)]
+    gateway: Option<String>,
+
+    /// Also re-download datasets whose data file is still present
+    #[arg(long)]
+    force: bool,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum RestoreOutcome {
+    Restored,
+    AlreadyPresent,
+}
+
+impl RestoreCmd {
+    /// Rebuilds `store_root` from the backups recorded in the catalog. Every
+    /// dataset is attempted; the command fails if any of them could not be
+    /// restored.
+    pub async fn execute(
+        &self,
+        catalog: &Catalog,
+        storage: &dyn StorageBackend,
+        store_root: &Path,
+    ) -> Result<(), String> {
+        let records: Vec<DatasetRecord> = catalog
+            .all()
+            .map_err(|err| format!("Failed to read catalog: {}", err))?
+            .into_iter()
+            .filter(|record| {
+                self.owner
+                    .as_ref()
+                    .is_none_or(|owner| &record.owner == owner)
+            })
+            .filter(|record| {
+                self.dataset
+                    .as_ref()
+                    .is_none_or(|dataset| &record.file_id == dataset)
+            })
+            .collect();
+        if records.is_empty() {
+            return Err("No matching datasets in the catalog".to_string());
+        }
+
+        let (mut restored, mut failed) = (0, 0);
+        for record in records {
+            match restore_dataset(
+                catalog,
+                storage,
+                self.gateway.as_deref(),
+                store_root,
+                record.clone(),
+                self.force,
+            )
+            .await
+            {
+                Ok(RestoreOutcome::Restored) => {
+                    restored += 1;
+                    log::info!("♻️ Restored {}/{}", record.owner, record.file_id);
+                }
+                Ok(RestoreOutcome::AlreadyPresent) => {
+                    log::info!("{}/{} is already present", record.owner, record.file_id);
+                }
+                Err(err) => {
+                    failed += 1;
+                    log::error!(
+                        "Restoring {}/{} failed 😭. Error: {}",
+                        record.owner,
+                        record.file_id,
+                        err
+                    );
+                }
+            }
+        }
+        log::info!("Restored {} datasets, {} failed", restored, failed);
+        if failed > 0 {
+            return Err(format!("{} datasets could not be restored", failed));
+        }
+        Ok(())
+    }
+}
+
+/// Fetches the backup of `record` by its CID, checks it against the size and
+/// SHA-256 recorded when it was stored and moves it into
+/// `store_root/<owner>/<file_id>/`. The key envelope and FHE server key backed
+/// up with it are restored the same way, each checked against its receipt.
+pub async fn restore_dataset(
+    catalog: &Catalog,
+    storage: &dyn StorageBackend,
+    gateway: Option<&str>,
+    store_root: &Path,
+    mut record: DatasetRecord,
+    force: bool,
+) -> io::Result<RestoreOutcome> {
+    let dataset_dir = store_root.join(&record.owner).join(&record.file_id);
+    let data_path = dataset_dir.join(&record.data_file);
+    let mut outcome = RestoreOutcome::AlreadyPresent;
+
+    if force || !data_path.is_file() || hash_file(&data_path)?.1 != record.sha256 {
+        let cid = record
+            .cid
+            .clone()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "No backup CID recorded"))?;
+        restore_file(
+            storage,
+            gateway,
+            &cid,
+            &data_path,
+            Some(record.size),
+            &record.sha256,
+        )
+        .await?;
+        outcome = RestoreOutcome::Restored;
+    }
+
+    if let Some(receipt) = &record.receipt {
+        if let Some(envelope) = &receipt.envelope {
+            let envelope_path = dataset_dir.join(ENVELOPE_FILE);
+            if restore_companion(storage, gateway, envelope, &envelope_path, force).await? {
+                outcome = RestoreOutcome::Restored;
+            }
+        }
+        if let (Some(key_id), Some(server_key)) = (&receipt.server_key_id, &receipt.server_key) {
+            let key_path = fhe_keys::server_key_path(&record.owner, key_id)?;
+            if restore_companion(storage, gateway, server_key, &key_path, force).await? {
+                outcome = RestoreOutcome::Restored;
+            }
+        }
+    }
+
+    if outcome == RestoreOutcome::Restored {
+        record.present = true;
+        catalog.put(&record)?;
+    }
+    Ok(outcome)
+}
+
+/// Restores `backup` to `path` unless an identical file is already there.
+async fn restore_companion(
+    storage: &dyn StorageBackend,
+    gateway: Option<&str>,
+    backup: &CompanionBackup,
+    path: &Path,
+    force: bool,
+) -> io::Result<bool> {
+    if !force && path.is_file() && hash_file(path)?.1 == backup.sha256 {
+        return Ok(false);
+    }
+    restore_file(storage, gateway, &backup.cid, path, None, &backup.sha256).await?;
+    Ok(true)
+}
+
+/// Downloads `cid` next to `path`, checks it and moves it into place.
+async fn restore_file(
+    storage: &dyn StorageBackend,
+    gateway: Option<&str>,
+    cid: &str,
+    path: &Path,
+    expected_size: Option<u64>,
+    expected_sha256: &str,
+) -> io::Result<()> {
+    // Download next to the destination so the final move is a rename
+    let dir = path.parent().unwrap_or(Path::new("."));
+    fs::create_dir_all(dir)?;
+    let partial = tempfile::NamedTempFile::new_in(dir)?;
+    match gateway {
+        Some(gateway) => {
+            let url = format!("{}/ipfs/{}", gateway.trim_end_matches('/'), cid);
+            let response = reqwest::get(url).await.map_err(other)?;
+            download(response, partial.path()).await?
+        }
+        None => storage.get(cid, partial.path()).await?,
+    };
+
+    let (size, sha256) = hash_file(partial.path())?;
+    if expected_size.unwrap_or(size) != size || sha256 != expected_sha256 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "Backup {} does not match the catalog: got {} bytes ({}), expected {}",
+                cid, size, sha256, expected_sha256
+            ),
+        ));
+    }
+    partial.persist(path).map_err(|err| err.error)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::catalog::BackupReceipt;
+    use crate::storage::LocalStore;
+    use std::path::PathBuf;
+
+    const OWNER: &str = "0xf39fd6e51aad88f6f4ce6ab8827279cfffb92266";
+
+    struct Setup {
+        _dir: tempfile::TempDir,
+        catalog: Catalog,
+        storage: LocalStore,
+        store: PathBuf,
+        backups: PathBuf,
+    }
+
+    /// A dataset with its data and key envelope backed up to a local store,
+    /// then deleted from `store`.
+    async fn backed_up() -> (Setup, DatasetRecord) {
+        let dir = tempfile::tempdir().unwrap();
+        let catalog = Catalog::open(&dir.path().join("catalog")).unwrap();
+        let backups = dir.path().join("backups");
+        let storage = LocalStore::new(&backups).unwrap();
+        let store = dir.path().join("store");
+        let dataset_dir = store.join(OWNER).join("data");
+        fs::create_dir_all(&dataset_dir).unwrap();
+        let data_path = dataset_dir.join("enc_data.b64");
+        fs::write(&data_path, b"ciphertext").unwrap();
+        let envelope_path = dataset_dir.join(ENVELOPE_FILE);
+        fs::write(&envelope_path, b"{}").unwrap();
+
+        let mut record = DatasetRecord::new(OWNER, "data", "", &data_path).unwrap();
+        let stored = storage.put(&data_path).await.unwrap();
+        let mut receipt = BackupReceipt::new("local", &stored.id, &stored.name, 10, 10);
+        receipt.envelope = Some(CompanionBackup {
+            cid: storage.put(&envelope_path).await.unwrap().id,
+            sha256: hash_file(&envelope_path).unwrap().1,
+        });
+        record.set_receipt(receipt);
+        catalog.put(&record).unwrap();
+        fs::remove_dir_all(&dataset_dir).unwrap();
+
+        let setup = Setup {
+            _dir: dir,
+            catalog,
+            storage,
+            store,
+            backups,
+        };
+        (setup, record)
+    }
+
+    async fn restore(setup: &Setup, record: &DatasetRecord) -> io::Result<RestoreOutcome> {
+        restore_dataset(
+            &setup.catalog,
+            &setup.storage,
+            None,
+            &setup.store,
+            record.clone(),
+            false,
+        )
+        .await
+    }
+
+    #[tokio::test]
+    async fn restores_the_data_and_envelope() {
+        let (setup, record) = backed_up().await;
+        assert_eq!(
+            restore(&setup, &record).await.unwrap(),
+            RestoreOutcome::Restored
+        );
+        let dataset_dir = setup.store.join(OWNER).join("data");
+        assert_eq!(
+            fs::read(dataset_dir.join("enc_data.b64")).unwrap(),
+            b"ciphertext"
+        );
+        assert_eq!(fs::read(dataset_dir.join(ENVELOPE_FILE)).unwrap(), b"{}");
+        assert_eq!(
+            restore(&setup, &record).await.unwrap(),
+            RestoreOutcome::AlreadyPresent
+        );
+    }
+
+    #[tokio::test]
+    async fn rejects_a_backup_with_another_hash() {
+        let (setup, record) = backed_up().await;
+        // Same size, other content
+        fs::write(
+            setup.backups.join(record.cid.as_ref().unwrap()),
+            b"CIPHERTEXT",
+        )
+        .unwrap();
+        let err = restore(&setup, &record).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(err.to_string().contains("does not match"), "{}", err);
+        let dataset_dir = setup.store.join(OWNER).join("data");
+        assert!(!dataset_dir.join("enc_data.b64").exists());
+    }
+
+    #[tokio::test]
+    async fn rejects_an_envelope_with_another_hash() {
+        let (setup, mut record) = backed_up().await;
+        let receipt = record.receipt.as_mut().unwrap();
+        receipt.envelope.as_mut().unwrap().sha256 = "0".repeat(64);
+        let err = restore(&setup, &record).await.unwrap_err();
+        assert!(err.to_string().contains("does not match"), "{}", err);
+        let dataset_dir = setup.store.join(OWNER).join("data");
+        assert!(!dataset_dir.join(ENVELOPE_FILE).exists());
+    }
+}
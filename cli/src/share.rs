@@ -0,0 +1,178 @@
+use crate::envelope::{PrivateKey, PublicKey};
+use crate::file_cipher;
+use crate::pre::{self, ReencryptResult};
+use base64::engine::general_purpose::STANDARD as BASE64_STANDARD;
+use base64::read::DecoderReader;
+use base64::Engine;
+use clap::Parser;
+use serde_json::json;
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+const DEFAULT_NODE_URL: &str = "http://localhost:8000";
+/// Written by `key-gen`.
+const DEFAULT_PRIVATE_KEY: &str = "keys/private_key.pem";
+
+/// Lets a buyer decrypt an AES dataset stored with `--proxy-reencryption` by
+/// handing the node a re-encryption key for them.
+#[derive(Debug, Clone, Parser)]
+pub struct GrantAccessCmd {
+    /// Address the dataset is stored under
+    #[arg(short, long)]
+    address: String,
+
+    /// File id of the dataset, as given when storing it
+    #[arg(short, long)]
+    dataset: String,
+
+    /// Buyer's secp256k1 public key
+    #[arg(short, long)]
+    grantee: PathBuf,
+
+    /// Owner's secp256k1 private key the AES key was wrapped for
+    #[arg(long, default_value = DEFAULT_PRIVATE_KEY)]
+    owner_key: PathBuf,
+
+    #[arg(long, default_value = DEFAULT_NODE_URL)]
+    node: String,
+}
+
+/// Decrypts a dataset shared through `grant-access` with the buyer's key.
+#[derive(Debug, Clone, Parser)]
+pub struct OpenSharedCmd {
+    /// Address the dataset is stored under
+    #[arg(short, long)]
+    address: String,
+
+    /// File id of the dataset, as given when storing it
+    #[arg(short, long)]
+    dataset: String,
+
+    /// Encrypted data file (enc_data.b64), e.g. fetched from the dataset's CID
+    #[arg(short, long)]
+    input: PathBuf,
+
+    #[arg(short, long)]
+    output: PathBuf,
+
+    /// Buyer's secp256k1 private key
+    #[arg(long, default_value = DEFAULT_PRIVATE_KEY)]
+    key: PathBuf,
+
+    #[arg(long, default_value = DEFAULT_NODE_URL)]
+    node: String,
+}
+
+fn read_private_key(path: &Path) -> Result<PrivateKey, String> {
+    let pem = fs::read_to_string(path)
+        .map_err(|err| format!("Unable to read private key {}: {}", path.display(), err))?;
+    PrivateKey::from_pem(&pem)
+}
+
+fn read_public_key(path: &Path) -> Result<PublicKey, String> {
+    let pem = fs::read_to_string(path)
+        .map_err(|err| format!("Unable to read public key {}: {}", path.display(), err))?;
+    PublicKey::from_pem(&pem)
+}
+
+impl GrantAccessCmd {
+    pub async fn execute(&self) -> Result<(), String> {
+        let owner_key = read_private_key(&self.owner_key)?;
+        let grantee = read_public_key(&self.grantee)?;
+        let kfrag = pre::issue(&owner_key, &grantee)?;
+        let fingerprint = grantee.fingerprint()?;
+        log::info!(
+            "Granting {} access to {}/{}",
+            fingerprint,
+            self.address,
+            self.dataset
+        );
+
+        let response = reqwest::Client::new()
+            .post(format!(
+                "{}/reencryption-key",
+                self.node.trim_end_matches('/')
+            ))
+            .json(&json!({
+                "address": self.address,
+                "filename": self.dataset,
+                "delegating_key": owner_key.public_key().to_pem()?,
+                "grantee_key": grantee.to_pem()?,
+                "kfrag": BASE64_STANDARD.encode(kfrag),
+            }))
+            .send()
+            .await
+            .map_err(|err| format!("Failed to reach the node: {}", err))?;
+        if !response.status().is_success() {
+            return Err(format!(
+                "Node refused the re-encryption key, status {}",
+                response.status()
+            ));
+        }
+        log::info!(
+            "✅ Access granted, the buyer fetches their key from /reencrypt/{}/{}/{}",
+            self.address,
+            self.dataset,
+            fingerprint
+        );
+        Ok(())
+    }
+}
+
+impl OpenSharedCmd {
+    pub async fn execute(&self) -> Result<(), String> {
+        let key = read_private_key(&self.key)?;
+        let fingerprint = key.public_key().fingerprint()?;
+        let response = reqwest::get(format!(
+            "{}/reencrypt/{}/{}/{}",
+            self.node.trim_end_matches('/'),
+            self.address,
+            self.dataset,
+            fingerprint
+        ))
+        .await
+        .map_err(|err| format!("Failed to reach the node: {}", err))?;
+        if !response.status().is_success() {
+            return Err(format!(
+                "No access to {}/{} for {}, status {}",
+                self.address,
+                self.dataset,
+                fingerprint,
+                response.status()
+            ));
+        }
+        let shared: ReencryptResult = response
+            .json()
+            .await
+            .map_err(|err| format!("Invalid node response: {}", err))?;
+        let decode = |value: &str| {
+            BASE64_STANDARD
+                .decode(value)
+                .map_err(|err| format!("Invalid node response: {}", err))
+        };
+        let symmetric_key = pre::open(
+            &key,
+            &PublicKey::from_pem(&shared.delegating_key)?,
+            &decode(&shared.wrapped_key)?,
+            &decode(&shared.capsule_frag)?,
+        )?;
+
+        // Decrypt next to the output, named after the extension once it is known
+        fs::create_dir_all(&self.output).map_err(|err| err.to_string())?;
+        let partial = self.output.join(format!("{}.partial", self.dataset));
+        let input = File::open(&self.input)
+            .map_err(|err| format!("Unable to read {}: {}", self.input.display(), err))?;
+        let mut writer = BufWriter::new(File::create(&partial).map_err(|err| err.to_string())?);
+        let extension = file_cipher::decrypt(
+            DecoderReader::new(BufReader::new(input), &BASE64_STANDARD),
+            &mut writer,
+            &symmetric_key,
+        )?;
+        writer.flush().map_err(|err| err.to_string())?;
+        let output = self.output.join(format!("{}.{}", self.dataset, extension));
+        fs::rename(&partial, &output).map_err(|err| err.to_string())?;
+        log::info!("🔓 Decrypted {} to {}", self.dataset, output.display());
+        Ok(())
+    }
+}
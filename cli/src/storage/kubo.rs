@@ -0,0 +1,146 @@
+use super::{download, file_info, other, ObjectStat, StorageBackend, StoredObject};
+use reqwest::multipart;
+use reqwest::{Body, Client, RequestBuilder};
+use serde::Deserialize;
+use std::io;
+use std::path::Path;
+use tokio_util::io::ReaderStream;
+
+/// Response of `/api/v0/add`, which Lighthouse mirrors.
+#[derive(Deserialize, Debug)]
+pub(crate) struct AddResponse {
+    #[serde(rename = "Name")]
+    pub name: String,
+    #[serde(rename = "Hash")]
+    pub hash: String,
+    #[serde(rename = "Size")]
+    pub size: String,
+}
+
+/// Sends the file at `path` as the `file` part of an IPFS add request,
+/// streaming it from disk under its own name and content type.
+pub(crate) async fn add(request: RequestBuilder, path: &Path) -> io::Result<StoredObject> {
+    let (file_name, file_size) = file_info(path)?;
+    let file = tokio::fs::File::open(path).await?;
+    let content_type = mime_guess::from_path(path).first_or_octet_stream();
+    let form = multipart::Form::new().part(
+        "file",
+        multipart::Part::stream_with_length(Body::wrap_stream(ReaderStream::new(file)), file_size)
+            .file_name(file_name.clone())
+            .mime_str(content_type.as_ref())
+            .map_err(other)?,
+    );
+    log::info!("Uploading {} ({} bytes)", file_name, file_size);
+
+    let response = request.multipart(form).send().await.map_err(other)?;
+    if !response.status().is_success() {
+        log::error!(
+            "OOpss! Failed to upload file. Status: {}",
+            response.status()
+        );
+        return Err(other(format!(
+            "Failed to upload file, status {}",
+            response.status()
+        )));
+    }
+    let added: AddResponse = response.json().await.map_err(other)?;
+    let stored_size = verify_size(&added, file_size)?;
+    Ok(StoredObject {
+        id: added.hash,
+        name: added.name,
+        size: file_size,
+        stored_size,
+    })
+}
+
+/// IPFS reports the size of the DAG, which is the file plus a little UnixFS
+/// framing per 256 KiB block; anything outside that range means the upload
+/// was cut short or is not the file we sent.
+fn verify_size(added: &AddResponse, file_size: u64) -> io::Result<u64> {
+    let stored_size: u64 = added
+        .size
+        .parse()
+        .map_err(|_| other(format!("Invalid size {:?} in add response", added.size)))?;
+    let max_overhead = file_size / 1024 + 1024;
+    if stored_size < file_size || stored_size > file_size + max_overhead {
+        log::error!(
+            "OOpss! Stored {} bytes of a {} byte file",
+            stored_size,
+            file_size
+        );
+        return Err(other(format!(
+            "Upload size mismatch: sent {} bytes, stored {}",
+            file_size, stored_size
+        )));
+    }
+    Ok(stored_size)
+}
+
+/// An IPFS node reached through the Kubo RPC API, e.g. a local `ipfs daemon`.
+pub struct Kubo {
+    api_url: String,
+    client: Client,
+}
+
+#[derive(Deserialize)]
+struct FilesStat {
+    #[serde(rename = "Size")]
+    size: u64,
+}
+
+impl Kubo {
+    pub fn new(api_url: &str) -> Self {
+        Kubo {
+            api_url: api_url.trim_end_matches('/').to_string(),
+            client: Client::new(),
+        }
+    }
+
+    fn rpc(&self, command: &str, arg: &str) -> RequestBuilder {
+        self.client
+            .post(format!("{}/api/v0/{}", self.api_url, command))
+            .query(&[("arg", arg)])
+    }
+}
+
+#[rocket::async_trait]
+impl StorageBackend for Kubo {
+    fn name(&self) -> &'static str {
+        "kubo"
+    }
+
+    async fn put(&self, path: &Path) -> io::Result<StoredObject> {
+        let request = self
+            .client
+            .post(format!("{}/api/v0/add", self.api_url))
+            .query(&[("pin", "true"), ("cid-version", "1")]);
+        add(request, path).await
+    }
+
+    async fn get(&self, id: &str, dest: &Path) -> io::Result<u64> {
+        let response = self.rpc("cat", id).send().await.map_err(other)?;
+        download(response, dest).await
+    }
+
+    async fn pin(&self, id: &str) -> io::Result<()> {
+        self.rpc("pin/add", id)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(other)?;
+        Ok(())
+    }
+
+    async fn stat(&self, id: &str) -> io::Result<ObjectStat> {
+        let stat: FilesStat = self
+            .rpc("files/stat", &format!("/ipfs/{}", id))
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(other)?
+            .json()
+            .await
+            .map_err(other)?;
+        Ok(ObjectStat { size: stat.size })
+    }
+}
@@ -0,0 +1,81 @@
+use super::{file_info, ObjectStat, StorageBackend, StoredObject};
+use sha2::{Digest, Sha256};
+use std::io;
+use std::path::{Path, PathBuf};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+/// Keeps backups in a directory on the node, named by the SHA-256 of their
+/// content. Stands in for a remote backend in tests and offline setups.
+pub struct LocalStore {
+    root: PathBuf,
+}
+
+impl LocalStore {
+    pub fn new(root: &Path) -> Result<Self, String> {
+        std::fs::create_dir_all(root)
+            .map_err(|err| format!("Unable to create {}: {}", root.display(), err))?;
+        Ok(LocalStore {
+            root: root.to_path_buf(),
+        })
+    }
+
+    fn object_path(&self, id: &str) -> io::Result<PathBuf> {
+        if id.len() != 64 || !id.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Invalid object id {}", id),
+            ));
+        }
+        Ok(self.root.join(id))
+    }
+}
+
+#[rocket::async_trait]
+impl StorageBackend for LocalStore {
+    fn name(&self) -> &'static str {
+        "local"
+    }
+
+    async fn put(&self, path: &Path) -> io::Result<StoredObject> {
+        let (name, size) = file_info(path)?;
+        let mut input = tokio::fs::File::open(path).await?;
+        let partial = tempfile::NamedTempFile::new_in(&self.root)?;
+        let mut output = tokio::fs::File::create(partial.path()).await?;
+        let mut hasher = Sha256::new();
+        let mut buffer = vec![0u8; 64 * 1024];
+        loop {
+            let read = input.read(&mut buffer).await?;
+            if read == 0 {
+                break;
+            }
+            hasher.update(&buffer[..read]);
+            output.write_all(&buffer[..read]).await?;
+        }
+        output.flush().await?;
+        let id = format!("{:x}", hasher.finalize());
+        partial
+            .persist(self.root.join(&id))
+            .map_err(|err| err.error)?;
+        Ok(StoredObject {
+            id,
+            name,
+            size,
+            stored_size: size,
+        })
+    }
+
+    async fn get(&self, id: &str, dest: &Path) -> io::Result<u64> {
+        tokio::fs::copy(self.object_path(id)?, dest).await
+    }
+
+    async fn pin(&self, id: &str) -> io::Result<()> {
+        self.stat(id).await.map(|_| ())
+    }
+
+    async fn stat(&self, id: &str) -> io::Result<ObjectStat> {
+        let metadata = tokio::fs::metadata(self.object_path(id)?).await?;
+        Ok(ObjectStat {
+            size: metadata.len(),
+        })
+    }
+}
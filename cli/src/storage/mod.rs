@@ -0,0 +1,126 @@
+pub(crate) mod kubo;
+mod local;
+mod s3;
+
+use crate::lighthouse::Lighthouse;
+use clap::{Args, ValueEnum};
+use futures_util::StreamExt;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::io::AsyncWriteExt;
+
+pub use kubo::Kubo;
+pub use local::LocalStore;
+pub use s3::S3Store;
+
+const DEFAULT_KUBO_URL: &str = "http://127.0.0.1:5001";
+const DEFAULT_LOCAL_DIR: &str = "backups";
+
+/// Where a stored file ended up: a CID for IPFS backends, an object key
+/// for S3 and a content hash for the local backend.
+#[derive(Debug, Clone)]
+pub struct StoredObject {
+    pub id: String,
+    pub name: String,
+    /// Bytes sent.
+    pub size: u64,
+    /// Size the backend reports for the object, which on IPFS includes the
+    /// DAG framing.
+    pub stored_size: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct ObjectStat {
+    pub size: u64,
+}
+
+/// Off-node storage that datasets are backed up to after they are stored.
+#[rocket::async_trait]
+pub trait StorageBackend: Send + Sync {
+    /// Short name used in logs.
+    fn name(&self) -> &'static str;
+
+    /// Uploads the file at `path`, streaming it from disk.
+    async fn put(&self, path: &Path) -> io::Result<StoredObject>;
+
+    /// Downloads object `id` into `dest`, returning the number of bytes written.
+    async fn get(&self, id: &str, dest: &Path) -> io::Result<u64>;
+
+    /// Makes sure object `id` is retained by the backend.
+    async fn pin(&self, id: &str) -> io::Result<()>;
+
+    async fn stat(&self, id: &str) -> io::Result<ObjectStat>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum StorageKind {
+    /// Lighthouse (Filecoin), authenticated with LH_API
+    Lighthouse,
+    /// IPFS node speaking the Kubo HTTP API
+    Kubo,
+    /// S3 compatible object storage, configured with S3_BUCKET, S3_REGION,
+    /// AWS_ACCESS_KEY_ID and AWS_SECRET_ACCESS_KEY
+    S3,
+    /// Directory on the node itself, for testing
+    Local,
+}
+
+#[derive(Debug, Clone, Args)]
+pub struct StorageArgs {
+    /// Backend datasets are backed up to
+    #[arg(long, value_enum, default_value_t = StorageKind::Lighthouse)]
+    storage: StorageKind,
+
+    /// Kubo API or S3 endpoint URL
+    #[arg(long)]
+    storage_url: Option<String>,
+
+    /// Directory used by the local backend
+    #[arg(long, default_value = DEFAULT_LOCAL_DIR)]
+    storage_dir: PathBuf,
+}
+
+impl StorageArgs {
+    pub fn backend(&self) -> Result<Arc<dyn StorageBackend>, String> {
+        let backend: Arc<dyn StorageBackend> = match self.storage {
+            StorageKind::Lighthouse => Arc::new(Lighthouse::from_env()?),
+            StorageKind::Kubo => Arc::new(Kubo::new(
+                self.storage_url.as_deref().unwrap_or(DEFAULT_KUBO_URL),
+            )),
+            StorageKind::S3 => Arc::new(S3Store::from_env(self.storage_url.as_deref())?),
+            StorageKind::Local => Arc::new(LocalStore::new(&self.storage_dir)?),
+        };
+        log::info!("Using the {} storage backend", backend.name());
+        Ok(backend)
+    }
+}
+
+/// Writes a response body to `dest` as it arrives.
+pub(crate) async fn download(response: reqwest::Response, dest: &Path) -> io::Result<u64> {
+    let response = response.error_for_status().map_err(other)?;
+    let mut file = tokio::fs::File::create(dest).await?;
+    let mut written = 0;
+    let mut body = response.bytes_stream();
+    while let Some(chunk) = body.next().await {
+        let chunk = chunk.map_err(other)?;
+        file.write_all(&chunk).await?;
+        written += chunk.len() as u64;
+    }
+    file.flush().await?;
+    Ok(written)
+}
+
+/// Name and size of the file about to be uploaded.
+pub(crate) fn file_info(path: &Path) -> io::Result<(String, u64)> {
+    let name = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Invalid file name"))?
+        .to_string();
+    Ok((name, std::fs::metadata(path)?.len()))
+}
+
+pub(crate) fn other<E: ToString>(err: E) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, err.to_string())
+}
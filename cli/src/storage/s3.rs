@@ -0,0 +1,152 @@
+use super::{download, file_info, other, ObjectStat, StorageBackend, StoredObject};
+use dotenv::dotenv;
+use reqwest::header::{CONTENT_LENGTH, CONTENT_TYPE};
+use reqwest::{Body, Client, Url};
+use rusty_s3::{Bucket, Credentials, S3Action, UrlStyle};
+use std::io;
+use std::path::{Component, Path};
+use std::time::Duration;
+use tokio_util::io::ReaderStream;
+
+/// How long the presigned request URLs stay valid.
+const SIGNATURE_TTL: Duration = Duration::from_secs(60 * 60);
+
+/// An S3 compatible bucket (AWS, MinIO, R2, ...). Objects are keyed by the
+/// path of the file on the node, e.g. `store/<address>/<filename>/enc_data.b64`.
+pub struct S3Store {
+    bucket: Bucket,
+    credentials: Credentials,
+    client: Client,
+}
+
+impl S3Store {
+    /// Configured from `S3_BUCKET`, `S3_REGION`, `S3_ENDPOINT`, `AWS_ACCESS_KEY_ID`,
+    /// `AWS_SECRET_ACCESS_KEY` and `AWS_SESSION_TOKEN`, also looking in `.env`.
+    /// `endpoint` takes precedence over `S3_ENDPOINT`.
+    pub fn from_env(endpoint: Option<&str>) -> Result<Self, String> {
+        dotenv().ok();
+        let var = |name: &str| {
+            std::env::var(name).map_err(|_| format!("{} must be set for the s3 backend", name))
+        };
+        let region = std::env::var("S3_REGION").unwrap_or_else(|_| "us-east-1".to_string());
+        let endpoint = match endpoint {
+            Some(endpoint) => endpoint.to_string(),
+            None => std::env::var("S3_ENDPOINT")
+                .unwrap_or_else(|_| format!("https://s3.{}.amazonaws.com", region)),
+        };
+        let endpoint =
+            Url::parse(&endpoint).map_err(|err| format!("Invalid S3 endpoint: {}", err))?;
+        let bucket = Bucket::new(endpoint, UrlStyle::Path, var("S3_BUCKET")?, region)
+            .map_err(|err| format!("Invalid S3 bucket: {}", err))?;
+        let key = var("AWS_ACCESS_KEY_ID")?;
+        let secret = var("AWS_SECRET_ACCESS_KEY")?;
+        let credentials = match std::env::var("AWS_SESSION_TOKEN") {
+            Ok(token) => Credentials::new_with_token(key, secret, token),
+            Err(_) => Credentials::new(key, secret),
+        };
+        Ok(S3Store {
+            bucket,
+            credentials,
+            client: Client::new(),
+        })
+    }
+}
+
+fn object_key(path: &Path) -> io::Result<String> {
+    let parts: Vec<&str> = path
+        .components()
+        .filter_map(|component| match component {
+            Component::Normal(part) => part.to_str(),
+            _ => None,
+        })
+        .collect();
+    if parts.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("No object key for {}", path.display()),
+        ));
+    }
+    Ok(parts.join("/"))
+}
+
+#[rocket::async_trait]
+impl StorageBackend for S3Store {
+    fn name(&self) -> &'static str {
+        "s3"
+    }
+
+    async fn put(&self, path: &Path) -> io::Result<StoredObject> {
+        let (name, size) = file_info(path)?;
+        let key = object_key(path)?;
+        let file = tokio::fs::File::open(path).await?;
+        let content_type = mime_guess::from_path(path).first_or_octet_stream();
+        let url = self
+            .bucket
+            .put_object(Some(&self.credentials), &key)
+            .sign(SIGNATURE_TTL);
+        log::info!(
+            "Uploading {} ({} bytes) to s3://{}",
+            key,
+            size,
+            self.bucket.name()
+        );
+        self.client
+            .put(url)
+            .header(CONTENT_LENGTH, size)
+            .header(CONTENT_TYPE, content_type.as_ref())
+            .body(Body::wrap_stream(ReaderStream::new(file)))
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(other)?;
+
+        let stored = self.stat(&key).await?;
+        if stored.size != size {
+            return Err(other(format!(
+                "Upload size mismatch: sent {} bytes, stored {}",
+                size, stored.size
+            )));
+        }
+        Ok(StoredObject {
+            id: key,
+            name,
+            size,
+            stored_size: stored.size,
+        })
+    }
+
+    async fn get(&self, id: &str, dest: &Path) -> io::Result<u64> {
+        let url = self
+            .bucket
+            .get_object(Some(&self.credentials), id)
+            .sign(SIGNATURE_TTL);
+        let response = self.client.get(url).send().await.map_err(other)?;
+        download(response, dest).await
+    }
+
+    /// Objects persist until deleted, pinning only checks the object exists.
+    async fn pin(&self, id: &str) -> io::Result<()> {
+        self.stat(id).await.map(|_| ())
+    }
+
+    async fn stat(&self, id: &str) -> io::Result<ObjectStat> {
+        let url = self
+            .bucket
+            .head_object(Some(&self.credentials), id)
+            .sign(SIGNATURE_TTL);
+        let response = self
+            .client
+            .head(url)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(other)?;
+        let size = response
+            .headers()
+            .get(CONTENT_LENGTH)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse().ok())
+            .ok_or_else(|| other(format!("No size reported for {}", id)))?;
+        Ok(ObjectStat { size })
+    }
+}
@@ -1,54 +1,151 @@
+use crate::access::{AccessControl, Grant, Operation};
+use crate::auth::{nonce_handler, parse_address, AuthenticatedAddress};
+use crate::backup::{BackupJob, BackupQueue};
+use crate::catalog::{check_id, EncryptionMode};
+use crate::catalog::{BackupReceipt, Catalog, DatasetRecord};
+use crate::container::{Encoding, EncryptedColumn, EncryptedFields, FheDataset};
 use crate::decrypt::decrypt;
-use crate::lighthouse::upload_file;
+use crate::envelope::{KeyEnvelope, PublicKey};
+use crate::fhe_keys;
+use crate::pre::{self, ReencryptResult, ReencryptionKey, ReencryptionKeys};
+use crate::restore::RestoreCmd;
+use crate::storage::StorageArgs;
 use crate::zk_proof::generate_proof;
-use base64::decode;
-use clap::Parser;
+use base64::engine::general_purpose::STANDARD as BASE64_STANDARD;
+use base64::read::DecoderReader;
+use base64::Engine;
+use clap::{Parser, Subcommand};
 use lazy_static::lazy_static;
+use rayon::prelude::*;
 use rocket::data::ToByteUnit;
 use rocket::fs::NamedFile;
-use rocket::http::ContentType;
+use rocket::http::{ContentType, Header};
 use rocket::serde::json::json;
-use rocket::{get, post, routes, Data};
+use rocket::{delete, get, post, routes, Data, Responder, State};
 use rocket_cors;
 use rocket_multipart_form_data::{
     MultipartFormData, MultipartFormDataField, MultipartFormDataOptions,
 };
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
 use std::fmt::{Display, Formatter};
 use std::fs::File;
-use std::io::{self, Cursor, Read, Write};
-use std::path::PathBuf;
+use std::io::{self, BufReader, Read};
+use std::path::{Path, PathBuf};
 use std::sync::Mutex;
-use tfhe::integer::{RadixCiphertext, ServerKey};
+use tfhe::integer::prelude::ServerKeyDefaultCMux;
+use tfhe::integer::{
+    BooleanBlock, IntegerCiphertext, IntegerRadixCiphertext, RadixCiphertext, ServerKey,
+    SignedRadixCiphertext,
+};
 
 #[derive(Serialize)]
 struct PubkeyResult {
     pubkey: String,
 }
 
+#[derive(Serialize)]
+struct StoreResult {
+    dataset_id: String,
+    backup: BackupJob,
+}
+
+#[derive(Deserialize)]
+struct ReencryptionKeyInput {
+    address: String,
+    filename: String,
+    /// PEM of the owner key the dataset's AES key is wrapped for
+    delegating_key: String,
+    /// PEM of the buyer's key
+    grantee_key: String,
+    /// Base64 encoded key fragment from `grant-access`
+    kfrag: String,
+}
+
+#[derive(Serialize)]
+struct GrantResult {
+    dataset_id: String,
+    grantee: String,
+}
+
+#[derive(Deserialize)]
+struct GrantInput {
+    address: String,
+    filename: String,
+    grantee: String,
+    operations: Vec<Operation>,
+    /// Unix time the grant stops working at, never when omitted
+    expires_at: Option<u64>,
+    /// Operations the grantee may run in total, unlimited when omitted
+    max_queries: Option<u64>,
+}
+
+/// Progress of a dataset's backup, with the receipt once it is pinned.
+#[derive(Serialize)]
+struct BackupStatusResult {
+    dataset_id: String,
+    backup: Option<BackupJob>,
+    receipt: Option<BackupReceipt>,
+}
+
+/// What an owner or buyer needs to fetch a dataset's backup and check it is
+/// the ciphertext the node holds.
+#[derive(Serialize)]
+struct CidResult {
+    dataset_id: String,
+    owner: String,
+    file_id: String,
+    cid: String,
+    sha256: String,
+    size: u64,
+    receipt: Option<BackupReceipt>,
+}
+
 const DEFAULT_KEY_PATH: &str = "/keys";
+const DEFAULT_CATALOG_PATH: &str = "catalog";
+const STORE_ROOT: &str = "store";
+/// Uploads are streamed to a temporary file, so this only bounds disk use.
+const MAX_DATA_SIZE: u64 = 64 * 1024 * 1024 * 1024;
 
 lazy_static! {
     static ref KEY_PATH: Mutex<String> = Mutex::new(String::new());
-    static ref USER_DATA: Mutex<HashMap<String, Vec<UserState>>> = Mutex::new(HashMap::new());
+    static ref PRIVATE_KEY_PATH: Mutex<PathBuf> = Mutex::new(PathBuf::new());
 }
 
 #[derive(Debug, Clone, Parser)]
 pub struct ZenNodeCmd {
     #[arg(short, long, default_value = DEFAULT_KEY_PATH)]
     key_file: PathBuf,
+
+    /// Private key unwrapping the AES keys of datasets, private_key.pem next to
+    /// the key file by default
+    #[arg(long)]
+    private_key: Option<PathBuf>,
+
+    #[arg(long, default_value = DEFAULT_CATALOG_PATH)]
+    catalog: PathBuf,
+
+    #[command(flatten)]
+    storage: StorageArgs,
+
+    #[command(subcommand)]
+    command: Option<ZenNodeSubcommand>,
 }
 
-#[derive(Debug, Clone, Serialize)]
-struct UserState {
-    key: String,
-    description: String,
-    file_id: String,
+#[derive(Debug, Clone, Subcommand)]
+pub enum ZenNodeSubcommand {
+    /// Rebuild store/ from the backups recorded in the catalog, then exit
+    Restore(RestoreCmd),
 }
 
 impl ZenNodeCmd {
     pub async fn execute(&self) -> Result<(), String> {
+        if let Some(ZenNodeSubcommand::Restore(restore)) = &self.command {
+            let catalog = Catalog::open(&self.catalog)?;
+            let storage = self.storage.backend()?;
+            return restore
+                .execute(&catalog, storage.as_ref(), Path::new(STORE_ROOT))
+                .await;
+        }
         if !self.key_file.exists() {
             log::error!(
                 "Key Path Not Found 🧐 Please cross check: Path: {:?}",
@@ -65,8 +162,34 @@ impl ZenNodeCmd {
             log::error!("Just .pem files allowed!! Use key-gen command to generate one");
             return Err("Invalid key path, should be a .pem file".to_string());
         }
+        let private_key_path = self
+            .private_key
+            .clone()
+            .unwrap_or_else(|| key_path.with_file_name("private_key.pem"));
+        if !private_key_path.exists() {
+            log::warn!(
+                "Private key not found at {:?}, downloads will fail",
+                private_key_path
+            );
+        }
+        *PRIVATE_KEY_PATH.lock().unwrap() = private_key_path;
         // Create store directory
         let _ = std::fs::create_dir_all("store/");
+        let catalog = Catalog::open(&self.catalog)?;
+        let changed = catalog
+            .reconcile(Path::new(STORE_ROOT))
+            .map_err(|err| format!("Failed to reconcile catalog with store: {}", err))?;
+        let storage = self.storage.backend()?;
+        // Backups run in the background and pick up where the last run stopped
+        let backups = BackupQueue::open(&catalog)?;
+        for (record, data_path) in changed {
+            backups
+                .enqueue(&record, &data_path)
+                .map_err(|err| format!("Failed to queue backup: {}", err))?;
+        }
+        tokio::spawn(backups.clone().run(catalog.clone(), storage));
+        let reencryption_keys = ReencryptionKeys::open(&catalog)?;
+        let access = AccessControl::open(&catalog)?;
         log::info!(
             "✨Zen-node✨ Started on http://localhost:8000/ \n You're ready to store and compute"
         );
@@ -85,10 +208,24 @@ impl ZenNodeCmd {
                     pubkey_handler,
                     userdata_handler,
                     alldata_handler,
-                    compute_handler
+                    dataset_cid_handler,
+                    dataset_backup_handler,
+                    download_handler,
+                    reencryption_key_handler,
+                    reencrypt_handler,
+                    revoke_reencryption_key_handler,
+                    grant_handler,
+                    grants_handler,
+                    revoke_handler,
+                    compute_handler,
+                    nonce_handler
                 ],
             )
             .attach(cors)
+            .manage(catalog)
+            .manage(backups)
+            .manage(reencryption_keys)
+            .manage(access)
             .launch()
             .await;
         Ok(())
@@ -99,11 +236,15 @@ impl ZenNodeCmd {
 async fn store_handler(
     content_type: &ContentType,
     data: Data<'_>,
+    catalog: &State<Catalog>,
+    backups: &State<BackupQueue>,
+    auth: AuthenticatedAddress,
 ) -> Result<String, std::io::Error> {
     log::info!("🚛 🚛 Data Coming In !!");
     let options = MultipartFormDataOptions::with_multipart_form_data_fields(vec![
-        MultipartFormDataField::file("data").size_limit(u64::from(1000.mebibytes())),
+        MultipartFormDataField::file("data").size_limit(MAX_DATA_SIZE),
         MultipartFormDataField::file("enc_symm_key").size_limit(u64::from(32.mebibytes())),
+        MultipartFormDataField::file("server_key").size_limit(u64::from(1000.mebibytes())),
         MultipartFormDataField::text("address"),
         MultipartFormDataField::text("filename"),
         MultipartFormDataField::text("description"),
@@ -123,43 +264,76 @@ async fn store_handler(
 
     let data_file = multi_form_data.files.get("data");
     let enc_symm_key_file = multi_form_data.files.get("enc_symm_key");
+    let server_key_file = multi_form_data.files.get("server_key");
     let address = multi_form_data.texts.get("address").unwrap();
     let filename = multi_form_data.texts.get("filename").unwrap();
     let description = multi_form_data.texts.get("description").unwrap();
 
-    let address = &address[0].text;
     let filename = &filename[0].text;
     let description = &description[0].text;
+    let address = auth.ensure(&address[0].text)?;
+    check_id("address", address)?;
+    check_id("filename", filename)?;
 
-    // Limit the scope of the MutexGuard
-    let mut data_path_final: String = String::new();
-    {
-        let mut user_state = USER_DATA.lock().unwrap();
+    // Key envelopes are checked before anything is written
+    let enc_symm_key = match enc_symm_key_file {
+        Some(enc_symm_key_file_fields) => {
+            let encoded = std::fs::read(&enc_symm_key_file_fields[0].path)?;
+            let envelope = BASE64_STANDARD
+                .decode(&encoded)
+                .map_err(|err| err.to_string())
+                .and_then(|bytes| KeyEnvelope::from_upload(&bytes))
+                .map_err(|err| {
+                    log::error!("Data Store Failed 😭. Error: {}", err);
+                    io::Error::new(io::ErrorKind::InvalidInput, err)
+                })?;
+            check_node_slot(&envelope);
+            Some(encoded)
+        }
+        None => {
+            log::warn!("Encrypted symmetric key file not found in the form data");
+            None
+        }
+    };
+
+    // FHE server keys are stored once per owner and referenced by datasets
+    if let Some(server_key_fields) = server_key_file {
+        let encoded = std::fs::read(&server_key_fields[0].path)?;
+        fhe_keys::store_server_key(address, &encoded)?;
+    }
 
+    let data_path_final: String;
+    let record: DatasetRecord;
+    {
         // Create the directory for the files
         let file_path = format!("store/{}/{}", address, filename);
         let _ = std::fs::create_dir_all(&file_path);
 
         if let Some(data_file_fields) = data_file {
             let data_file_field = &data_file_fields[0];
-            let data_file_name = data_file_field.file_name.as_ref().unwrap();
-            let final_path = format!("{}/{}", file_path, data_file_name);
+            let encryption = EncryptionMode::from_file_id(filename);
+            let final_path = format!("{}/{}", file_path, encryption.data_file());
             data_path_final = final_path.clone();
-            let mut file = File::create(final_path)?;
-            let mut temp_file = File::open(&data_file_field.path)?;
-            let mut buffer: Vec<u8> = Vec::new();
-            temp_file.read_to_end(&mut buffer)?;
-            file.write_all(&buffer)?;
-
-            // Update user state with data file
-            let user_entry = user_state
-                .entry(address.clone())
-                .or_insert(Vec::<UserState>::new());
-            user_entry.push(UserState {
-                key: String::new(),
-                description: description.clone(),
-                file_id: filename.clone(),
-            });
+            if encryption == EncryptionMode::Fhe {
+                let encoded = File::open(&data_file_field.path)?;
+                let decoded = DecoderReader::new(BufReader::new(encoded), &BASE64_STANDARD);
+                if let Some(key_id) = FheDataset::peek_key_id(decoded)? {
+                    if !fhe_keys::has_server_key(address, &key_id)? {
+                        log::error!("Data Store Failed 😭. Error: Unknown server key {}", key_id);
+                        return Err(io::Error::new(
+                            io::ErrorKind::NotFound,
+                            format!("Upload server key {} along with the dataset", key_id),
+                        ));
+                    }
+                }
+            }
+            // The upload is already spooled to disk, copy it rather than buffer it
+            std::fs::copy(&data_file_field.path, final_path)?;
+
+            // Record the dataset in the catalog
+            record =
+                DatasetRecord::new(address, filename, description, Path::new(&data_path_final))?;
+            catalog.put(&record)?;
         } else {
             log::error!("Data Store Failed 😭. Error: Data file not found");
             return Err(std::io::Error::new(
@@ -169,16 +343,41 @@ async fn store_handler(
         }
     }
 
-    if let Some(enc_symm_key_file_fields) = enc_symm_key_file {
-        ()
-    } else {
-        log::warn!("Encrypted symmetric key file not found in the form data");
+    if let Some(encoded) = enc_symm_key {
+        std::fs::write(
+            format!("store/{}/{}/enc_sym_keys.b64", address, filename),
+            encoded,
+        )?;
     }
 
-    // Make the asynchronous call outside the lock scope
-    let _lh_resp: crate::lighthouse::LighthouseResponse =
-        upload_file(&data_path_final).await.unwrap();
-    Ok(format!("{:?}", _lh_resp))
+    // The upload to the storage backend happens in the background
+    let backup = backups.enqueue(&record, Path::new(&data_path_final))?;
+    let res = StoreResult {
+        dataset_id: record.id,
+        backup,
+    };
+    Ok(serde_json::to_string(&res).unwrap())
+}
+
+/// Warns when an uploaded key envelope has no slot this node can unwrap.
+fn check_node_slot(envelope: &KeyEnvelope) {
+    let pubkey_path = KEY_PATH.lock().unwrap().clone();
+    let node_fingerprint = std::fs::read_to_string(pubkey_path)
+        .ok()
+        .and_then(|pem| PublicKey::from_pem(&pem).ok())
+        .and_then(|key| key.fingerprint().ok());
+    match node_fingerprint {
+        Some(node_fingerprint) => {
+            if envelope.proxy_slot().is_some() {
+                log::info!("Dataset is shared through proxy re-encryption, the node holds no key");
+            } else if envelope.slot_for(&node_fingerprint).is_none() {
+                log::warn!(
+                    "Key envelope has no slot for this node's key, it cannot decrypt the data"
+                );
+            }
+        }
+        None => log::warn!("Unable to read the node public key"),
+    }
 }
 
 #[get("/pubkey")]
@@ -197,38 +396,408 @@ async fn pubkey_handler() -> Result<String, std::io::Error> {
 }
 
 #[get("/userdata/<address>")]
-async fn userdata_handler(address: String) -> Result<String, std::io::Error> {
+async fn userdata_handler(
+    address: String,
+    catalog: &State<Catalog>,
+) -> Result<String, std::io::Error> {
     println!("User Data Requested for {}", address);
-    let mut user_state = USER_DATA.lock().unwrap();
-    if let Some(data) = user_state.get(&address) {
-        Ok(serde_json::to_string(data).unwrap())
-    } else {
+    let data = catalog.by_owner(&address.to_lowercase())?;
+    if data.is_empty() {
         Err(std::io::Error::new(
             std::io::ErrorKind::NotFound,
             "User Data not found",
         ))
+    } else {
+        Ok(serde_json::to_string(&data).unwrap())
+    }
+}
+
+#[get("/dataset/<id>/cid")]
+async fn dataset_cid_handler(
+    id: String,
+    catalog: &State<Catalog>,
+) -> Result<Option<String>, std::io::Error> {
+    let record = match catalog.by_id(&id)? {
+        Some(record) => record,
+        None => return Ok(None),
+    };
+    let cid = match &record.cid {
+        Some(cid) => cid.clone(),
+        None => return Ok(None),
+    };
+    let res = CidResult {
+        dataset_id: record.id,
+        owner: record.owner,
+        file_id: record.file_id,
+        cid,
+        sha256: record.sha256,
+        size: record.size,
+        receipt: record.receipt,
+    };
+    Ok(Some(serde_json::to_string(&res).unwrap()))
+}
+
+#[get("/dataset/<id>/backup")]
+async fn dataset_backup_handler(
+    id: String,
+    catalog: &State<Catalog>,
+    backups: &State<BackupQueue>,
+) -> Result<Option<String>, std::io::Error> {
+    let record = match catalog.by_id(&id)? {
+        Some(record) => record,
+        None => return Ok(None),
+    };
+    let res = BackupStatusResult {
+        backup: backups.status(&record.owner, &record.file_id)?,
+        dataset_id: record.id,
+        receipt: record.receipt,
+    };
+    Ok(Some(serde_json::to_string(&res).unwrap()))
+}
+
+/// A decrypted dataset, sent as an attachment named after the dataset.
+#[derive(Responder)]
+struct Download {
+    file: NamedFile,
+    disposition: Header<'static>,
+}
+
+/// Unwraps the dataset key with the node's private key and streams the
+/// decrypted file to the owner or a grantee allowed to download.
+#[get("/download/<address>/<filename>")]
+async fn download_handler(
+    address: String,
+    filename: String,
+    catalog: &State<Catalog>,
+    access: &State<AccessControl>,
+    auth: AuthenticatedAddress,
+) -> Result<Option<Download>, io::Error> {
+    let address = address.to_lowercase();
+    let record = match catalog.get(&address, &filename)? {
+        Some(record) if record.present => record,
+        _ => return Ok(None),
+    };
+    if record.encryption == EncryptionMode::Fhe {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "FHE datasets can only be computed on",
+        ));
+    }
+    access.authorize(&address, &filename, &auth, Operation::Download)?;
+    log::info!("📦 Download of {}/{} requested", address, filename);
+
+    let dataset_dir = Path::new(STORE_ROOT)
+        .join(&record.owner)
+        .join(&record.file_id);
+    let private_key_path = PRIVATE_KEY_PATH.lock().unwrap().clone();
+    let file = decrypt(
+        dataset_dir
+            .join(&record.data_file)
+            .to_string_lossy()
+            .to_string(),
+        dataset_dir
+            .join("enc_sym_keys.b64")
+            .to_string_lossy()
+            .to_string(),
+        private_key_path.to_string_lossy().to_string(),
+        std::env::temp_dir().to_string_lossy().to_string(),
+    )
+    .await;
+    let file = match file {
+        Ok(file) => file,
+        Err(err) => {
+            log::error!("Download Failed 😭. Error: {}", err);
+            access.refund(&address, &filename, &auth)?;
+            return Err(io::Error::new(io::ErrorKind::Other, err));
+        }
+    };
+
+    let name = match file.path().extension().and_then(|ext| ext.to_str()) {
+        Some(ext) => format!("{}.{}", record.file_id, ext),
+        None => record.file_id.clone(),
+    };
+    Ok(Some(Download {
+        file,
+        disposition: Header::new(
+            "Content-Disposition",
+            format!("attachment; filename=\"{}\"", name.replace('"', "")),
+        ),
+    }))
+}
+
+/// Stores a buyer's re-encryption key. It needs no signature headers: the key
+/// fragment only verifies when signed by the owner key the dataset's AES key
+/// is wrapped for.
+#[post("/reencryption-key", data = "<input>")]
+async fn reencryption_key_handler(
+    input: rocket::serde::json::Json<ReencryptionKeyInput>,
+    catalog: &State<Catalog>,
+    keys: &State<ReencryptionKeys>,
+) -> Result<Option<String>, io::Error> {
+    let record = match catalog.get(&input.address.to_lowercase(), &input.filename)? {
+        Some(record) => record,
+        None => return Ok(None),
+    };
+    let envelope = read_envelope(&record)?;
+    let slot = envelope.proxy_slot().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Dataset was not stored for proxy re-encryption",
+        )
+    })?;
+    let invalid = |err: String| io::Error::new(io::ErrorKind::InvalidInput, err);
+    let delegating = PublicKey::from_pem(&input.delegating_key).map_err(invalid)?;
+    let grantee = PublicKey::from_pem(&input.grantee_key).map_err(invalid)?;
+    if delegating.fingerprint().map_err(invalid)? != slot.fingerprint {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "Key fragment is not from the dataset owner's key",
+        ));
+    }
+    let kfrag = BASE64_STANDARD
+        .decode(&input.kfrag)
+        .map_err(|err| invalid(err.to_string()))?;
+    pre::verify(&kfrag, &delegating, &grantee)
+        .map_err(|err| io::Error::new(io::ErrorKind::PermissionDenied, err))?;
+
+    let key = ReencryptionKey::new(
+        &record.owner,
+        &record.file_id,
+        &delegating,
+        &grantee,
+        &kfrag,
+    )
+    .map_err(invalid)?;
+    keys.put(&key)?;
+    log::info!(
+        "🔑 {} can now decrypt {}/{}",
+        key.grantee,
+        record.owner,
+        record.file_id
+    );
+    let res = GrantResult {
+        dataset_id: record.id,
+        grantee: key.grantee,
+    };
+    Ok(Some(serde_json::to_string(&res).unwrap()))
+}
+
+/// Re-encrypts the owner's key slot for `grantee` (a key fingerprint). The
+/// answer only helps the holder of the grantee's private key, so it is served
+/// without signature headers.
+#[get("/reencrypt/<address>/<filename>/<grantee>")]
+async fn reencrypt_handler(
+    address: String,
+    filename: String,
+    grantee: String,
+    catalog: &State<Catalog>,
+    keys: &State<ReencryptionKeys>,
+) -> Result<Option<String>, io::Error> {
+    let address = address.to_lowercase();
+    let key = match keys.get(&address, &filename, &grantee)? {
+        Some(key) => key,
+        None => return Ok(None),
+    };
+    let record = match catalog.get(&address, &filename)? {
+        Some(record) => record,
+        None => return Ok(None),
+    };
+    let envelope = read_envelope(&record)?;
+    let slot = envelope.proxy_slot().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Dataset is no longer stored for proxy re-encryption",
+        )
+    })?;
+    let invalid = |err: String| io::Error::new(io::ErrorKind::InvalidData, err);
+    let wrapped_key = BASE64_STANDARD
+        .decode(&slot.wrapped_key)
+        .map_err(|err| invalid(err.to_string()))?;
+    let kfrag = BASE64_STANDARD
+        .decode(&key.kfrag)
+        .map_err(|err| invalid(err.to_string()))?;
+    let capsule_frag = pre::reencrypt(
+        &wrapped_key,
+        &kfrag,
+        &PublicKey::from_pem(&key.delegating_key).map_err(invalid)?,
+        &PublicKey::from_pem(&key.grantee_key).map_err(invalid)?,
+    )
+    .map_err(invalid)?;
+    log::info!(
+        "Re-encrypted the key of {}/{} for {}",
+        address,
+        filename,
+        grantee
+    );
+
+    let res = ReencryptResult {
+        grantee: key.grantee,
+        delegating_key: key.delegating_key,
+        wrapped_key: slot.wrapped_key.clone(),
+        capsule_frag: BASE64_STANDARD.encode(capsule_frag),
+        cid: record.cid,
+    };
+    Ok(Some(serde_json::to_string(&res).unwrap()))
+}
+
+/// Drops a buyer's re-encryption key, the node can no longer re-encrypt the
+/// dataset key for them. Keys they fetched before stay usable.
+#[delete("/reencryption-key/<address>/<filename>/<grantee>")]
+async fn revoke_reencryption_key_handler(
+    address: String,
+    filename: String,
+    grantee: String,
+    keys: &State<ReencryptionKeys>,
+    auth: AuthenticatedAddress,
+) -> Result<Option<String>, io::Error> {
+    let address = auth.ensure(&address)?;
+    match keys.remove(address, &filename, &grantee)? {
+        Some(key) => {
+            log::info!(
+                "Removed the re-encryption key of {} for {}/{}",
+                grantee,
+                address,
+                filename
+            );
+            Ok(Some(serde_json::to_string(&key).unwrap()))
+        }
+        None => Ok(None),
+    }
+}
+
+/// The key envelope uploaded along with `record`'s data.
+fn read_envelope(record: &DatasetRecord) -> io::Result<KeyEnvelope> {
+    let encoded = std::fs::read(
+        Path::new(STORE_ROOT)
+            .join(&record.owner)
+            .join(&record.file_id)
+            .join("enc_sym_keys.b64"),
+    )?;
+    BASE64_STANDARD
+        .decode(encoded)
+        .map_err(|err| err.to_string())
+        .and_then(|bytes| KeyEnvelope::from_bytes(&bytes))
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+}
+
+/// Lets `grantee` run the listed operations on one of the caller's datasets.
+#[post("/grants", data = "<input>")]
+async fn grant_handler(
+    input: rocket::serde::json::Json<GrantInput>,
+    catalog: &State<Catalog>,
+    access: &State<AccessControl>,
+    auth: AuthenticatedAddress,
+) -> Result<Option<String>, io::Error> {
+    let address = auth.ensure(&input.address)?;
+    let record = match catalog.get(address, &input.filename)? {
+        Some(record) => record,
+        None => return Ok(None),
+    };
+    if parse_address(&input.grantee).is_none() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Invalid grantee address",
+        ));
+    }
+    if input.operations.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Grant at least one operation",
+        ));
+    }
+    let grant = Grant::new(
+        &record.owner,
+        &record.file_id,
+        &input.grantee,
+        input.operations.clone(),
+        input.expires_at,
+        input.max_queries,
+    );
+    access.grant(&grant)?;
+    log::info!(
+        "🤝 {} can now use {}/{} for {:?}",
+        grant.grantee,
+        grant.owner,
+        grant.file_id,
+        grant.operations
+    );
+    Ok(Some(serde_json::to_string(&grant).unwrap()))
+}
+
+#[get("/grants/<address>/<filename>")]
+async fn grants_handler(
+    address: String,
+    filename: String,
+    access: &State<AccessControl>,
+    auth: AuthenticatedAddress,
+) -> Result<String, io::Error> {
+    let address = auth.ensure(&address)?;
+    Ok(serde_json::to_string(&access.list(address, &filename)?).unwrap())
+}
+
+#[delete("/grants/<address>/<filename>/<grantee>")]
+async fn revoke_handler(
+    address: String,
+    filename: String,
+    grantee: String,
+    access: &State<AccessControl>,
+    auth: AuthenticatedAddress,
+) -> Result<Option<String>, io::Error> {
+    let address = auth.ensure(&address)?;
+    match access.revoke(address, &filename, &grantee)? {
+        Some(grant) => {
+            log::info!("Revoked access of {} to {}/{}", grantee, address, filename);
+            Ok(Some(serde_json::to_string(&grant).unwrap()))
+        }
+        None => Ok(None),
     }
 }
 
 #[get("/alldata")]
-async fn alldata_handler() -> Result<String, std::io::Error> {
+async fn alldata_handler(catalog: &State<Catalog>) -> Result<String, std::io::Error> {
     println!("All user data requested");
-    let mut user_state = USER_DATA.lock().unwrap();
-    Ok(serde_json::to_string(&*user_state).unwrap())
+    Ok(serde_json::to_string(&catalog.grouped()?).unwrap())
 }
 
 /*  compute on fhe -> we need file path [already done] -> Type of compute [average ( int res ), total (int res),
-    gt, lt, gt_eq, lt_eq (bool res)]
+    min, max (int res), count (plain), compare total (bool res), count matching rows (int res),
+    compare rows (bool per row), variance and histogram (json object)]
 */
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Clone, Copy)]
 enum ComputeTypes {
     Average,
     Total,
-    GT,
-    LT,
-    GE,
-    LE,
+    Min,
+    Max,
+    Count,
+    /// Compares the total of the column to the threshold
+    CompareTotal,
+    /// Counts the rows comparing true to the threshold
+    CountMatching,
+    /// Compares every row to the threshold
+    CompareRows,
+    /// Mean, variance and standard deviation, from the encrypted sums of the
+    /// values and of their squares
+    Variance,
+    /// Number of rows in each bucket between consecutive `bounds`
+    Histogram,
+}
+
+impl ComputeTypes {
+    fn operation(&self) -> Operation {
+        match self {
+            ComputeTypes::Average => Operation::Average,
+            ComputeTypes::Total => Operation::Total,
+            ComputeTypes::Min => Operation::Min,
+            ComputeTypes::Max => Operation::Max,
+            ComputeTypes::Count => Operation::Count,
+            ComputeTypes::CompareTotal | ComputeTypes::CountMatching => Operation::Compare,
+            ComputeTypes::CompareRows => Operation::CompareRows,
+            ComputeTypes::Variance => Operation::Variance,
+            ComputeTypes::Histogram => Operation::Histogram,
+        }
+    }
 }
 
 impl Display for ComputeTypes {
@@ -236,10 +805,14 @@ impl Display for ComputeTypes {
         match self {
             ComputeTypes::Average => write!(f, "Average"),
             ComputeTypes::Total => write!(f, "Total"),
-            ComputeTypes::GT => write!(f, "GT"),
-            ComputeTypes::LT => write!(f, "LT"),
-            ComputeTypes::GE => write!(f, "GE"),
-            ComputeTypes::LE => write!(f, "LE"),
+            ComputeTypes::Min => write!(f, "Min"),
+            ComputeTypes::Max => write!(f, "Max"),
+            ComputeTypes::Count => write!(f, "Count"),
+            ComputeTypes::CompareTotal => write!(f, "CompareTotal"),
+            ComputeTypes::CountMatching => write!(f, "CountMatching"),
+            ComputeTypes::CompareRows => write!(f, "CompareRows"),
+            ComputeTypes::Variance => write!(f, "Variance"),
+            ComputeTypes::Histogram => write!(f, "Histogram"),
         }
     }
 }
@@ -249,13 +822,638 @@ struct ComputeInput {
     address: String,
     filename: String,
     compute_type: ComputeTypes,
-    threshold: Option<u32>,
+    /// How values compare to `threshold` in the comparison operations
+    comparison: Option<Comparison>,
+    /// In the units of the column, decimals included
+    threshold: Option<f64>,
+    /// Ascending bucket bounds of a histogram, each bucket including its
+    /// lower bound and excluding its upper one
+    bounds: Option<Vec<f64>>,
+    /// Encrypted column to compute on, the first one when omitted
+    column: Option<String>,
+    /// Only rows meeting this condition are aggregated
+    #[serde(rename = "where")]
+    filter: Option<Filter>,
+    /// Decimals an average keeps beyond those of the column, truncated
+    decimals: Option<u32>,
+}
+
+impl ComputeInput {
+    fn comparison(&self, encoding: Encoding) -> Result<(Comparison, Threshold), io::Error> {
+        match (self.comparison, self.threshold) {
+            (Some(comparison), Some(threshold)) => {
+                Ok((comparison, Threshold::new(threshold, encoding)))
+            }
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} needs a comparison and a threshold", self.compute_type),
+            )),
+        }
+    }
+
+    /// Extra decimals of the result, and the factor scaling the sum to them.
+    fn decimals(&self) -> Result<(u32, u64), io::Error> {
+        let decimals = match self.compute_type {
+            ComputeTypes::Average => self.decimals.unwrap_or(0),
+            _ => 0,
+        };
+        let factor = 10u64.checked_pow(decimals).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("An average keeps at most 19 decimals, not {}", decimals),
+            )
+        })?;
+        Ok((decimals, factor))
+    }
+
+    /// How the decrypt server reads the result of a column encoded as
+    /// `encoding`.
+    fn result_encoding(&self, encoding: Encoding) -> Result<Encoding, io::Error> {
+        let (decimals, _) = self.decimals()?;
+        Ok(Encoding {
+            scale: encoding.scale + decimals,
+            ..encoding
+        })
+    }
+}
+
+/// Radix ciphertexts the operations run on, unsigned or signed as the column
+/// was encoded.
+trait FheInteger: IntegerRadixCiphertext + Serialize {
+    /// The same value over `num_blocks` more blocks.
+    fn widen(&self, server_key: &ServerKey, num_blocks: usize) -> Self;
+
+    fn scalar_div(&self, server_key: &ServerKey, divisor: u64) -> Self;
+}
+
+impl FheInteger for RadixCiphertext {
+    fn widen(&self, server_key: &ServerKey, num_blocks: usize) -> Self {
+        server_key.extend_radix_with_trivial_zero_blocks_msb(self, num_blocks)
+    }
+
+    fn scalar_div(&self, server_key: &ServerKey, divisor: u64) -> Self {
+        server_key.scalar_div_parallelized(self, divisor)
+    }
+}
+
+impl FheInteger for SignedRadixCiphertext {
+    fn widen(&self, server_key: &ServerKey, num_blocks: usize) -> Self {
+        server_key.extend_radix_with_sign_msb(self, num_blocks)
+    }
+
+    fn scalar_div(&self, server_key: &ServerKey, divisor: u64) -> Self {
+        server_key.signed_scalar_div_parallelized(self, divisor as i64)
+    }
+}
+
+/// Largest magnitudes the operations can reach over a column, from its row
+/// count and declared maximum, or the whole width of its values when it has
+/// none. Accumulators get enough blocks to hold them, so results never wrap.
+struct WorstCase {
+    rows: u128,
+    max: u128,
+    signed: bool,
+    num_blocks: usize,
+    message_bits: usize,
+}
+
+impl WorstCase {
+    fn new(server_key: &ServerKey, column: &EncryptedColumn, num_blocks: usize) -> Self {
+        WorstCase::sized(
+            column.values.len() as u128,
+            column.max,
+            column.encoding.signed,
+            num_blocks,
+            server_key.message_modulus().0.ilog2() as usize,
+        )
+    }
+
+    /// `rows` values of `num_blocks` blocks, up to `max` in absolute value.
+    fn sized(
+        rows: u128,
+        max: Option<u64>,
+        signed: bool,
+        num_blocks: usize,
+        message_bits: usize,
+    ) -> Self {
+        let bits = num_blocks * message_bits;
+        let width_max = if signed {
+            1u128 << (bits - 1)
+        } else {
+            (1u128 << bits) - 1
+        };
+        WorstCase {
+            rows,
+            max: max.map_or(width_max, |max| width_max.min(max as u128)),
+            signed,
+            num_blocks,
+            message_bits,
+        }
+    }
+
+    /// Blocks holding any value up to `bound`, never fewer than the column's.
+    /// Decrypted results are 128-bit integers, larger bounds are refused.
+    fn blocks(&self, bound: Option<u128>, operation: &str) -> Result<usize, io::Error> {
+        let bits = bound
+            .map(|bound| (u128::BITS - bound.leading_zeros()) as usize)
+            .filter(|bits| *bits < 128)
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "{} over {} rows of up to {} could overflow 128 bits; \
+                         encrypt the column with a smaller --max-value or filter fewer rows",
+                        operation, self.rows, self.max
+                    ),
+                )
+            })?;
+        let bits = bits + self.signed as usize;
+        Ok(self.num_blocks.max(bits.div_ceil(self.message_bits)))
+    }
+
+    fn count_blocks(&self) -> Result<usize, io::Error> {
+        self.blocks(Some(self.rows), "Count")
+    }
+
+    fn sum_blocks(&self) -> Result<usize, io::Error> {
+        self.scaled_sum_blocks(1)
+    }
+
+    /// Blocks of a sum multiplied by `factor`.
+    fn scaled_sum_blocks(&self, factor: u64) -> Result<usize, io::Error> {
+        let bound = self
+            .rows
+            .checked_mul(self.max)
+            .and_then(|sum| sum.checked_mul(factor as u128));
+        self.blocks(bound, "Sum")
+    }
+
+    fn sum_squares_blocks(&self) -> Result<usize, io::Error> {
+        let bound = self
+            .max
+            .checked_mul(self.max)
+            .and_then(|square| square.checked_mul(self.rows));
+        self.blocks(bound, "Sum of squares")
+    }
+}
+
+/// `values` extended to `num_blocks` blocks.
+fn widened<T: FheInteger>(server_key: &ServerKey, values: Vec<T>, num_blocks: usize) -> Vec<T> {
+    values
+        .into_iter()
+        .map(
+            |value| match num_blocks.saturating_sub(value.blocks().len()) {
+                0 => value,
+                extra => value.widen(server_key, extra),
+            },
+        )
+        .collect()
+}
+
+/// Sum of `values` over `num_blocks` blocks.
+fn sum_values<T: FheInteger>(server_key: &ServerKey, values: Vec<T>, num_blocks: usize) -> T {
+    server_key
+        .sum_ciphertexts_parallelized(&widened(server_key, values, num_blocks))
+        .unwrap_or_else(|| server_key.create_trivial_zero_radix(num_blocks))
+}
+
+/// A threshold in the units of a column, as the encoded integers right below
+/// and above it, which are the same when it has no more decimals than the
+/// column keeps.
+#[derive(Clone, Copy)]
+struct Threshold {
+    floor: i64,
+    ceil: i64,
+}
+
+impl Threshold {
+    fn new(value: f64, encoding: Encoding) -> Self {
+        let scaled = value * 10f64.powi(encoding.scale as i32);
+        // Absorbs the float error of the scaling, e.g. 0.1 * 100
+        if (scaled - scaled.round()).abs() < 1e-6 {
+            let scaled = scaled.round() as i64;
+            Threshold {
+                floor: scaled,
+                ceil: scaled,
+            }
+        } else {
+            Threshold {
+                floor: scaled.floor() as i64,
+                ceil: scaled.ceil() as i64,
+            }
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Copy)]
+enum Comparison {
+    GT,
+    LT,
+    GE,
+    LE,
+    EQ,
+}
+
+impl Comparison {
+    fn apply<T: FheInteger>(
+        self,
+        server_key: &ServerKey,
+        value: &T,
+        threshold: Threshold,
+    ) -> BooleanBlock {
+        match self {
+            Comparison::GT => server_key.scalar_gt_parallelized(value, threshold.floor),
+            Comparison::LT => server_key.scalar_lt_parallelized(value, threshold.ceil),
+            Comparison::GE => server_key.scalar_ge_parallelized(value, threshold.ceil),
+            Comparison::LE => server_key.scalar_le_parallelized(value, threshold.floor),
+            Comparison::EQ if threshold.floor == threshold.ceil => {
+                server_key.scalar_eq_parallelized(value, threshold.floor)
+            }
+            // No encoded value equals a threshold between two of them
+            Comparison::EQ => server_key.create_trivial_boolean_block(false),
+        }
+    }
+}
+
+/// Compares every row to `threshold`, rows not meeting the filter comparing
+/// false.
+fn compare_rows<T: FheInteger>(
+    server_key: &ServerKey,
+    values: &[T],
+    conditions: Option<&[BooleanBlock]>,
+    comparison: Comparison,
+    threshold: Threshold,
+) -> Vec<BooleanBlock> {
+    values
+        .iter()
+        .enumerate()
+        .map(|(row, value)| {
+            let matches = comparison.apply(server_key, value, threshold);
+            match conditions {
+                Some(conditions) => server_key.boolean_bitand(&matches, &conditions[row]),
+                None => matches,
+            }
+        })
+        .collect()
+}
+
+/// Sums of the values and of their squares, and the number of rows, which the
+/// variance is derived from once decrypted. Each is as wide as its worst case.
+fn variance_sums<T: FheInteger>(
+    server_key: &ServerKey,
+    values: Vec<T>,
+    conditions: Option<&[BooleanBlock]>,
+    worst: &WorstCase,
+) -> Result<EncryptedFields, io::Error> {
+    let count_blocks = worst.count_blocks()?;
+    let square_blocks = worst.sum_squares_blocks()?;
+    let count: T = match conditions {
+        Some(conditions) => count_rows(server_key, conditions, count_blocks),
+        None => server_key.create_trivial_radix(values.len() as u64, count_blocks),
+    };
+    let zero: T = server_key.create_trivial_zero_radix(worst.num_blocks);
+    let values = select_rows(server_key, values, conditions, &zero);
+    let squares: Vec<T> = widened(server_key, values.clone(), square_blocks)
+        .iter()
+        .map(|wide| server_key.mul_parallelized(wide, wide))
+        .collect();
+    let sum = sum_values(server_key, values, worst.sum_blocks()?);
+    let sum_squares = sum_values(server_key, squares, square_blocks);
+    let field = |name: &str, value: T| {
+        (
+            name.to_string(),
+            RadixCiphertext::from_blocks(value.into_blocks()),
+        )
+    };
+    Ok(EncryptedFields {
+        fields: vec![
+            field("count", count),
+            field("sum", sum),
+            field("sum_squares", sum_squares),
+        ],
+        signed: T::IS_SIGNED,
+    })
+}
+
+/// Number of rows in each bucket between consecutive `bounds`, named after
+/// the bucket's range.
+fn histogram<T: FheInteger>(
+    server_key: &ServerKey,
+    values: &[T],
+    conditions: Option<&[BooleanBlock]>,
+    bounds: &[f64],
+    encoding: Encoding,
+    num_blocks: usize,
+) -> Result<EncryptedFields, io::Error> {
+    if bounds.len() < 2 || bounds.windows(2).any(|pair| pair[0] >= pair[1]) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Histogram needs at least two ascending bounds",
+        ));
+    }
+    let fields = bounds
+        .windows(2)
+        .map(|bucket| {
+            let lower = Threshold::new(bucket[0], encoding);
+            let upper = Threshold::new(bucket[1], encoding);
+            let in_bucket: Vec<BooleanBlock> = values
+                .iter()
+                .enumerate()
+                .map(|(row, value)| {
+                    let in_bucket = server_key.boolean_bitand(
+                        &Comparison::GE.apply(server_key, value, lower),
+                        &Comparison::LT.apply(server_key, value, upper),
+                    );
+                    match conditions {
+                        Some(conditions) => server_key.boolean_bitand(&in_bucket, &conditions[row]),
+                        None => in_bucket,
+                    }
+                })
+                .collect();
+            (
+                format!("{}..{}", bucket[0], bucket[1]),
+                count_rows(server_key, &in_bucket, num_blocks),
+            )
+        })
+        .collect();
+    Ok(EncryptedFields {
+        fields,
+        signed: false,
+    })
+}
+
+/// Condition on an encrypted column, checked homomorphically so the node
+/// never learns which rows meet it.
+#[derive(Deserialize)]
+struct Filter {
+    /// Column the condition is on, the computed one when omitted
+    column: Option<String>,
+    op: FilterOp,
+    /// In the units of the column, decimals included
+    value: f64,
+    /// Inclusive upper bound of `Between`, `value` being the lower one
+    upper: Option<f64>,
+}
+
+#[derive(Deserialize)]
+enum FilterOp {
+    GT,
+    LT,
+    GE,
+    LE,
+    EQ,
+    Between,
+}
+
+impl Filter {
+    /// Whether each row of `column` meets the condition, encrypted.
+    fn evaluate(
+        &self,
+        server_key: &ServerKey,
+        column: &EncryptedColumn,
+    ) -> Result<Vec<BooleanBlock>, io::Error> {
+        let upper = match (&self.op, self.upper) {
+            (FilterOp::Between, None) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "Between needs an upper bound",
+                ))
+            }
+            (_, upper) => Threshold::new(upper.unwrap_or_default(), column.encoding),
+        };
+        let lower = Threshold::new(self.value, column.encoding);
+        Ok(if column.encoding.signed {
+            self.evaluate_values(server_key, &column.signed_values(), lower, upper)
+        } else {
+            self.evaluate_values(server_key, &column.values, lower, upper)
+        })
+    }
+
+    fn evaluate_values<T: FheInteger>(
+        &self,
+        server_key: &ServerKey,
+        values: &[T],
+        lower: Threshold,
+        upper: Threshold,
+    ) -> Vec<BooleanBlock> {
+        values
+            .iter()
+            .map(|value| match self.op {
+                FilterOp::GT => Comparison::GT.apply(server_key, value, lower),
+                FilterOp::LT => Comparison::LT.apply(server_key, value, lower),
+                FilterOp::GE => Comparison::GE.apply(server_key, value, lower),
+                FilterOp::LE => Comparison::LE.apply(server_key, value, lower),
+                FilterOp::EQ => Comparison::EQ.apply(server_key, value, lower),
+                FilterOp::Between => server_key.boolean_bitand(
+                    &Comparison::GE.apply(server_key, value, lower),
+                    &Comparison::LE.apply(server_key, value, upper),
+                ),
+            })
+            .collect()
+    }
+}
+
+/// Replaces the values of rows not meeting the filter with `neutral`, which
+/// leaves the aggregate as if those rows were not there.
+fn select_rows<T: FheInteger>(
+    server_key: &ServerKey,
+    values: Vec<T>,
+    conditions: Option<&[BooleanBlock]>,
+    neutral: &T,
+) -> Vec<T> {
+    match conditions {
+        Some(conditions) => values
+            .iter()
+            .zip(conditions)
+            .map(|(value, condition)| {
+                server_key.if_then_else_parallelized(condition, value, neutral)
+            })
+            .collect(),
+        None => values,
+    }
+}
+
+/// Number of rows meeting the filter, encrypted.
+fn count_rows<T: FheInteger>(
+    server_key: &ServerKey,
+    conditions: &[BooleanBlock],
+    num_blocks: usize,
+) -> T {
+    let flags: Vec<T> = conditions
+        .iter()
+        .map(|condition| condition.clone().into_radix(num_blocks, server_key))
+        .collect();
+    server_key
+        .sum_ciphertexts_parallelized(&flags)
+        .unwrap_or_else(|| server_key.create_trivial_zero_radix(num_blocks))
+}
+
+/// What a computation produced: a result the node may see as is, or one only
+/// the client decrypt server can read.
+enum Computed {
+    Plain(String),
+    Encrypted(Vec<u8>),
+}
+
+fn encrypted<S: Serialize>(result: &S) -> Result<Computed, io::Error> {
+    bincode::serialize(result)
+        .map(Computed::Encrypted)
+        .map_err(|err| io::Error::new(io::ErrorKind::Other, err))
+}
+
+/// `result` together with the number of rows it was computed over, so the
+/// decrypt server can tell an aggregate of no rows from a real value.
+fn with_count<T: FheInteger>(count: T, result: T) -> Result<Computed, io::Error> {
+    let field = |name: &str, value: T| {
+        (
+            name.to_string(),
+            RadixCiphertext::from_blocks(value.into_blocks()),
+        )
+    };
+    encrypted(&EncryptedFields {
+        fields: vec![field("count", count), field("result", result)],
+        signed: T::IS_SIGNED,
+    })
+}
+
+/// Runs the requested operation over the values of a column.
+fn compute<T: FheInteger>(
+    input: &ComputeInput,
+    server_key: &ServerKey,
+    values: Vec<T>,
+    conditions: Option<&[BooleanBlock]>,
+    encoding: Encoding,
+    worst: &WorstCase,
+) -> Result<Computed, io::Error> {
+    let num_blocks = worst.num_blocks;
+    let zero: T = server_key.create_trivial_zero_radix(num_blocks);
+    match input.compute_type {
+        ComputeTypes::Average => {
+            let rows = values.len() as u64;
+            if rows == 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "Column has no values",
+                ));
+            }
+            // The sum is scaled first so the quotient keeps the decimals
+            let (_, factor) = input.decimals()?;
+            let sum_blocks = worst.scaled_sum_blocks(factor)?;
+            let values = select_rows(server_key, values, conditions, &zero);
+            let mut sum = sum_values(server_key, values, sum_blocks);
+            if factor > 1 {
+                sum = server_key.scalar_mul_parallelized(&sum, factor);
+            }
+            let (average, count) = match conditions {
+                Some(conditions) => {
+                    let count = count_rows::<T>(server_key, conditions, sum_blocks);
+                    (server_key.div_parallelized(&sum, &count), count)
+                }
+                None => (
+                    sum.scalar_div(server_key, rows),
+                    server_key.create_trivial_radix(rows, worst.count_blocks()?),
+                ),
+            };
+            with_count(count, average)
+        }
+        ComputeTypes::Total => {
+            let sum_blocks = worst.sum_blocks()?;
+            let values = select_rows(server_key, values, conditions, &zero);
+            encrypted(&sum_values(server_key, values, sum_blocks))
+        }
+        ComputeTypes::Min | ComputeTypes::Max => {
+            let neutral = match input.compute_type {
+                ComputeTypes::Min => server_key.create_trivial_max_radix(num_blocks),
+                _ => server_key.create_trivial_min_radix(num_blocks),
+            };
+            let count: T = match conditions {
+                Some(conditions) => count_rows(server_key, conditions, worst.count_blocks()?),
+                None => server_key.create_trivial_radix(values.len() as u64, worst.count_blocks()?),
+            };
+            // Reduced pairwise as a tree, so the comparisons of each level
+            // run in parallel
+            let extreme = select_rows(server_key, values, conditions, &neutral)
+                .into_par_iter()
+                .reduce_with(|left, right| match input.compute_type {
+                    ComputeTypes::Min => server_key.min_parallelized(&left, &right),
+                    _ => server_key.max_parallelized(&left, &right),
+                })
+                .ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::InvalidData, "Column has no values")
+                })?;
+            with_count(count, extreme)
+        }
+        // The number of rows is not secret to the node, so there is
+        // nothing to decrypt
+        ComputeTypes::Count => match conditions {
+            Some(conditions) => encrypted(&count_rows::<RadixCiphertext>(
+                server_key,
+                conditions,
+                worst.count_blocks()?,
+            )),
+            None => Ok(Computed::Plain(values.len().to_string())),
+        },
+        ComputeTypes::CompareTotal => {
+            let (comparison, threshold) = input.comparison(encoding)?;
+            let sum_blocks = worst.sum_blocks()?;
+            let values = select_rows(server_key, values, conditions, &zero);
+            let sum = sum_values(server_key, values, sum_blocks);
+            encrypted(&comparison.apply(server_key, &sum, threshold))
+        }
+        ComputeTypes::CountMatching => {
+            let (comparison, threshold) = input.comparison(encoding)?;
+            let matches = compare_rows(server_key, &values, conditions, comparison, threshold);
+            encrypted(&count_rows::<RadixCiphertext>(
+                server_key,
+                &matches,
+                worst.count_blocks()?,
+            ))
+        }
+        ComputeTypes::CompareRows => {
+            let (comparison, threshold) = input.comparison(encoding)?;
+            encrypted(&compare_rows(
+                server_key, &values, conditions, comparison, threshold,
+            ))
+        }
+        ComputeTypes::Variance => encrypted(&variance_sums(server_key, values, conditions, worst)?),
+        ComputeTypes::Histogram => {
+            let bounds = input.bounds.as_deref().unwrap_or_default();
+            encrypted(&histogram(
+                server_key,
+                &values,
+                conditions,
+                bounds,
+                encoding,
+                worst.count_blocks()?,
+            )?)
+        }
+    }
 }
 
 #[post("/compute", data = "<input>")]
 async fn compute_handler(
     input: rocket::serde::json::Json<ComputeInput>,
+    access: &State<AccessControl>,
+    auth: AuthenticatedAddress,
 ) -> Result<String, io::Error> {
+    let mut input = input.into_inner();
+    input.address = input.address.to_lowercase();
+    check_id("address", &input.address)?;
+    check_id("filename", &input.filename)?;
+    access.authorize(
+        &input.address,
+        &input.filename,
+        &auth,
+        input.compute_type.operation(),
+    )?;
+    let result = compute_dataset(&input).await;
+    if result.is_err() {
+        access.refund(&input.address, &input.filename, &auth)?;
+    }
+    result
+}
+
+async fn compute_dataset(input: &ComputeInput) -> Result<String, io::Error> {
     // Paths
     let initial_state = 1;
     let mut steps: Vec<i32> = vec![];
@@ -265,93 +1463,88 @@ async fn compute_handler(
         let mut file = File::open(format!("{}/fhe_enc_data.b64", data_dir))?;
         let mut data = Vec::new();
         file.read_to_end(&mut data)?;
-        let decoded_data = decode(&data).unwrap();
-        let serialized_data_bytes = decoded_data.as_slice();
-        let mut serialized_data = Cursor::new(serialized_data_bytes);
-        let server_key: ServerKey = bincode::deserialize_from(&mut serialized_data).unwrap();
-        let mut values: Vec<RadixCiphertext> = vec![];
-        while true {
-            let int: RadixCiphertext = match bincode::deserialize_from(&mut serialized_data) {
-                Ok(value) => value,
-                Err(err) => {
-                    eprintln!("Failed to deserialize data: {:?}", err);
-                    break;
+        let decoded_data = BASE64_STANDARD
+            .decode(&data)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        let dataset = FheDataset::from_bytes(&decoded_data).map_err(|err| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Failed to deserialize data: {}", err),
+            )
+        })?;
+        let column = dataset.column(input.column.as_deref()).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("Column not found, available: {:?}", dataset.column_names()),
+            )
+        })?;
+        let server_key = match (&dataset.server_key, &dataset.key_id) {
+            (Some(server_key), _) => std::sync::Arc::new(server_key.clone()),
+            (None, Some(key_id)) => fhe_keys::server_key(&input.address, key_id)?,
+            (None, None) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "Dataset references no server key",
+                ))
+            }
+        };
+        let server_key = server_key.as_ref();
+        let conditions = match &input.filter {
+            Some(filter) => {
+                let filter_column = match &filter.column {
+                    Some(name) => dataset.column(Some(name)).ok_or_else(|| {
+                        io::Error::new(
+                            io::ErrorKind::NotFound,
+                            format!(
+                                "Filter column not found, available: {:?}",
+                                dataset.column_names()
+                            ),
+                        )
+                    })?,
+                    None => column,
+                };
+                if filter_column.values.len() != column.values.len() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "Filter column has a different number of rows",
+                    ));
                 }
-            };
-            values.push(int);
-        }
-        let mut serial_res: Vec<u8> = Vec::new();
+                Some(filter.evaluate(server_key, filter_column)?)
+            }
+            None => None,
+        };
+        let conditions = conditions.as_deref();
+        let encoding = column.encoding;
+        let result_encoding = input.result_encoding(encoding)?;
+        let worst = WorstCase::new(server_key, column, dataset.num_blocks);
         steps.push(3);
         steps.push(4);
-        let compute_result: Result<String, _> = match input.compute_type {
-            ComputeTypes::Average => {
-                let mut sum: RadixCiphertext =
-                    ServerKey::sum_ciphertexts_parallelized(&server_key, &values).unwrap();
-                let count = values.len() as u32;
-                let average =
-                    ServerKey::scalar_div_assign_parallelized(&server_key, &mut sum, count);
-                let _ = bincode::serialize_into(&mut serial_res, &average);
-                let res = get_decoded_res(ComputeTypes::Average, serial_res)
-                    .await
-                    .unwrap();
-                Ok::<String, Box<dyn std::error::Error>>(res)
-            }
-            ComputeTypes::Total => {
-                let sum: RadixCiphertext =
-                    ServerKey::sum_ciphertexts_parallelized(&server_key, &values).unwrap();
-                let _ = bincode::serialize_into(&mut serial_res, &sum);
-                let res = get_decoded_res(ComputeTypes::Total, serial_res)
-                    .await
-                    .unwrap();
-                Ok(res)
-            }
-            ComputeTypes::GT => {
-                let threshold: u32 = input.threshold.unwrap();
-                let sum: RadixCiphertext =
-                    ServerKey::unchecked_sum_ciphertexts_vec_parallelized(&server_key, values)
-                        .unwrap();
-                let is_gt = ServerKey::scalar_gt_parallelized(&server_key, &sum, threshold);
-                let _ = bincode::serialize_into(&mut serial_res, &is_gt);
-                let res = get_decoded_res(ComputeTypes::GT, serial_res).await.unwrap();
-                Ok(res)
-            }
-            ComputeTypes::LT => {
-                let threshold: u32 = input.threshold.unwrap();
-                let sum: RadixCiphertext =
-                    ServerKey::unchecked_sum_ciphertexts_vec_parallelized(&server_key, values)
-                        .unwrap();
-                let is_gt = ServerKey::scalar_lt_parallelized(&server_key, &sum, threshold);
-                let _ = bincode::serialize_into(&mut serial_res, &is_gt);
-                let res = get_decoded_res(ComputeTypes::LT, serial_res).await.unwrap();
-                Ok(res)
-            }
-            ComputeTypes::GE => {
-                let threshold: u32 = input.threshold.unwrap();
-                let sum: RadixCiphertext =
-                    ServerKey::unchecked_sum_ciphertexts_vec_parallelized(&server_key, values)
-                        .unwrap();
-                let is_gt = ServerKey::scalar_ge_parallelized(&server_key, &sum, threshold);
-                let _ = bincode::serialize_into(&mut serial_res, &is_gt);
-                let res = get_decoded_res(ComputeTypes::GE, serial_res).await.unwrap();
-                Ok(res)
-            }
-            ComputeTypes::LE => {
-                let threshold: u32 = input.threshold.unwrap();
-                let sum: RadixCiphertext =
-                    ServerKey::unchecked_sum_ciphertexts_vec_parallelized(&server_key, values)
-                        .unwrap();
-                let is_gt = ServerKey::scalar_le_parallelized(&server_key, &sum, threshold);
-                let _ = bincode::serialize_into(&mut serial_res, &is_gt);
-                let res = get_decoded_res(ComputeTypes::GT, serial_res).await.unwrap();
-                Ok(res)
-            }
+        let computed = if encoding.signed {
+            let values = column.signed_values();
+            compute(input, server_key, values, conditions, encoding, &worst)?
+        } else {
+            let values = column.values.clone();
+            compute(input, server_key, values, conditions, encoding, &worst)?
         };
+        let compute_result = match computed {
+            Computed::Plain(result) => Ok(result),
+            Computed::Encrypted(serial_res) => {
+                get_decoded_res(
+                    input.compute_type,
+                    serial_res,
+                    dataset.key_id.as_deref(),
+                    result_encoding,
+                )
+                .await
+            }
+        }
+        .map_err(|err| io::Error::new(io::ErrorKind::Other, err.to_string()))?;
         steps.push(5);
         // send proof to chain, return result, proof and tx hash
-        let proof: String = generate_proof(initial_state, &steps).unwrap();
-        println!("{}", compute_result.as_ref().unwrap());
+        let proof: String = generate_proof(initial_state, &steps)?;
+        println!("{}", compute_result);
         let response_json = json!({
-            "compute_result": compute_result.unwrap(),
+            "compute_result": compute_result,
             "proof": proof
         });
         Ok(response_json.to_string())
@@ -365,11 +1558,19 @@ async fn compute_handler(
 async fn get_decoded_res(
     compute_type: ComputeTypes,
     serial_enc_output: Vec<u8>,
+    key_id: Option<&str>,
+    encoding: Encoding,
 ) -> Result<String, Box<dyn std::error::Error>> {
-    let output = reqwest::Client::new()
+    let mut request = reqwest::Client::new()
         .post("http://localhost:6000/process_job")
         .header("Content-Type", "application/octet-stream")
         .header("compute_type", compute_type.to_string())
+        .header("signed", encoding.signed.to_string())
+        .header("scale", encoding.scale.to_string());
+    if let Some(key_id) = key_id {
+        request = request.header("key_id", key_id);
+    }
+    let output = request
         .body(serial_enc_output)
         .send()
         .await
@@ -387,3 +1588,125 @@ async fn get_decoded_res(
         .unwrap();
     Ok(res)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A column of `bits`-bit values, two bits per block as `FHE_PARAM_SET` has.
+    fn worst_case(rows: u128, bits: usize, signed: bool, max: Option<u64>) -> WorstCase {
+        WorstCase::sized(rows, max, signed, bits / 2, 2)
+    }
+
+    #[test]
+    fn worst_case_keeps_the_column_width_when_sums_fit() {
+        for bits in [8, 16, 32, 64] {
+            let unsigned = worst_case(1, bits, false, None);
+            assert_eq!(unsigned.sum_blocks().unwrap(), bits / 2, "{}", bits);
+            assert_eq!(unsigned.count_blocks().unwrap(), bits / 2);
+            // The most negative value is sized by its magnitude, a bit wider than the column
+            let signed = worst_case(1, bits, true, None);
+            assert_eq!(signed.sum_blocks().unwrap(), bits / 2 + 1, "{}", bits);
+            let positive_max = (1u64 << (bits - 1)) - 1;
+            let signed = worst_case(1, bits, true, Some(positive_max));
+            assert_eq!(signed.sum_blocks().unwrap(), bits / 2, "{}", bits);
+        }
+    }
+
+    #[test]
+    fn worst_case_widens_sums_of_many_rows() {
+        // 1000 * 255 needs 18 bits
+        assert_eq!(worst_case(1000, 8, false, None).sum_blocks().unwrap(), 9);
+        // 1000 * 128 needs 17 bits, and a sign bit
+        assert_eq!(worst_case(1000, 8, true, None).sum_blocks().unwrap(), 9);
+        // 1000 * 65535 needs 26 bits
+        assert_eq!(worst_case(1000, 16, false, None).sum_blocks().unwrap(), 13);
+        // 3 * (2^32 - 1) needs 34 bits
+        assert_eq!(worst_case(3, 32, false, None).sum_blocks().unwrap(), 17);
+        // 2^20 * (2^64 - 1) needs 84 bits, 2^20 * 2^63 needs 84 and a sign bit
+        assert_eq!(
+            worst_case(1 << 20, 64, false, None).sum_blocks().unwrap(),
+            42
+        );
+        assert_eq!(
+            worst_case(1 << 20, 64, true, None).sum_blocks().unwrap(),
+            43
+        );
+        // Counting 1000 rows needs 10 bits, fewer than the column has
+        assert_eq!(worst_case(1000, 8, false, None).count_blocks().unwrap(), 5);
+        assert_eq!(
+            worst_case(1000, 32, false, None).count_blocks().unwrap(),
+            16
+        );
+    }
+
+    #[test]
+    fn worst_case_uses_the_declared_max() {
+        // 100.00 at scale 2: 10^6 rows of up to 10^4 need 34 bits
+        let worst = worst_case(1_000_000, 32, false, Some(10_000));
+        assert_eq!(worst.sum_blocks().unwrap(), 17);
+        // Averages scale the sum by 10^2 first: 10^12 needs 40 bits
+        assert_eq!(worst.scaled_sum_blocks(100).unwrap(), 20);
+        // A max above what the width holds changes nothing
+        let wide = worst_case(1000, 8, false, Some(u64::MAX));
+        assert_eq!(wide.sum_blocks().unwrap(), 9);
+    }
+
+    #[test]
+    fn worst_case_refuses_results_past_128_bits() {
+        // 2^64 * (2^64 - 1) needs 128 bits
+        let err = worst_case(1 << 64, 64, false, None)
+            .sum_blocks()
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert!(err.to_string().contains("128 bits"), "{}", err);
+        // Overflows u128 while sizing
+        assert!(worst_case(1 << 64, 64, false, None)
+            .scaled_sum_blocks(2)
+            .is_err());
+        assert!(worst_case(1, 64, false, None).sum_squares_blocks().is_err());
+        // One bit under the limit is fine, 2^63 * (2^64 - 1) needs 127 bits
+        assert_eq!(
+            worst_case(1 << 63, 64, false, None).sum_blocks().unwrap(),
+            64
+        );
+    }
+
+    /// `(floor, ceil)` of `value` on a column with `scale` decimals.
+    fn threshold(value: f64, scale: u32, signed: bool) -> (i64, i64) {
+        let threshold = Threshold::new(value, Encoding { signed, scale });
+        (threshold.floor, threshold.ceil)
+    }
+
+    #[test]
+    fn threshold_keeps_values_the_column_can_hold() {
+        assert_eq!(threshold(42.0, 0, false), (42, 42));
+        assert_eq!(threshold(1.5, 2, false), (150, 150));
+        // 0.1 * 100 and 1.15 * 100 are not whole numbers in floating point
+        assert_eq!(threshold(0.1, 2, false), (10, 10));
+        assert_eq!(threshold(1.15, 2, false), (115, 115));
+        assert_eq!(threshold(-2.25, 2, true), (-225, -225));
+    }
+
+    #[test]
+    fn threshold_rounds_fractions_both_ways() {
+        assert_eq!(threshold(2.5, 0, false), (2, 3));
+        assert_eq!(threshold(1.255, 2, false), (125, 126));
+        assert_eq!(threshold(0.001, 2, false), (0, 1));
+    }
+
+    #[test]
+    fn threshold_rounds_negative_fractions_away_from_and_towards_zero() {
+        assert_eq!(threshold(-2.5, 0, true), (-3, -2));
+        assert_eq!(threshold(-1.255, 2, true), (-126, -125));
+        assert_eq!(threshold(-0.001, 2, true), (-1, 0));
+    }
+
+    #[test]
+    fn threshold_saturates_beyond_i64() {
+        assert_eq!(threshold(1e30, 2, false), (i64::MAX, i64::MAX));
+        assert_eq!(threshold(-1e30, 2, true), (i64::MIN, i64::MIN));
+        assert_eq!(threshold(f64::INFINITY, 0, false), (i64::MAX, i64::MAX));
+        assert_eq!(threshold(9.2e18, 1, false), (i64::MAX, i64::MAX));
+    }
+}
@@ -11,9 +11,7 @@ pub fn generate_proof(initial_state: i32, steps: &[i32]) -> Result<String, std::
         "steps": steps
     });
 
-    let mut input_file = File::create("zk/input.json")
-        .map_err(|e| eprintln!("We're here1"))
-        .unwrap();
+    let mut input_file = File::create("zk/input.json")?;
     write!(input_file, "{}", input_data)?;
 
     // Generate witness
@@ -24,9 +22,7 @@ pub fn generate_proof(initial_state: i32, steps: &[i32]) -> Result<String, std::
             "zk/input.json",
             "zk/witness.wtns",
         ])
-        .output()
-        .map_err(|e| eprintln!("We're here2"))
-        .unwrap();
+        .output()?;
 
     // Generate the proof
     let out = Command::new("snarkjs")
@@ -38,9 +34,7 @@ pub fn generate_proof(initial_state: i32, steps: &[i32]) -> Result<String, std::
             "zk/proof.json",
             "zk/public.json",
         ])
-        .output()
-        .map_err(|e| eprintln!("We're here3, {}", e))
-        .unwrap();
+        .output()?;
     let formatted_proof = Command::new("snarkjs")
         .args(&[
             "zkey",
@@ -49,9 +43,7 @@ pub fn generate_proof(initial_state: i32, steps: &[i32]) -> Result<String, std::
             "zk/public.json",
             "zk/proof.json",
         ])
-        .output()
-        .map_err(|e| eprintln!("We're here3, {}", e))
-        .unwrap();
+        .output()?;
 
     println!("{:?}", formatted_proof);
 
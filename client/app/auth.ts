@@ -0,0 +1,17 @@
+const NODE_URL = "http://localhost:8000";
+
+// Fetches a one-time challenge from zen-node and signs it with the connected
+// wallet, returning the headers zen-node expects on authenticated endpoints.
+export async function authHeaders(
+  address: string,
+  signMessageAsync: (args: { message: string }) => Promise<string>
+): Promise<Record<string, string>> {
+  const resp = await fetch(`${NODE_URL}/auth/nonce/${address}`);
+  const challenge: { nonce: string; message: string } = await resp.json();
+  const signature = await signMessageAsync({ message: challenge.message });
+  return {
+    "x-zen-address": address,
+    "x-zen-nonce": challenge.nonce,
+    "x-zen-signature": signature,
+  };
+}
@@ -1,6 +1,7 @@
 import React, { useState } from "react";
 import Compute_handler from "../../../contract/compute_handler.json";
-import { useWriteContract } from "wagmi";
+import { useAccount, useSignMessage, useWriteContract } from "wagmi";
+import { authHeaders } from "../auth";
 import { parseEther } from "viem";
 import {
   arbitrumSepolia,
@@ -20,6 +21,8 @@ export default function ComputePopup({
   const [tval, setTval] = useState(0);
   const [chain, setChain] = useState("");
   const { writeContractAsync } = useWriteContract();
+  const { address: account } = useAccount();
+  const { signMessageAsync } = useSignMessage();
   const [complete, setComplete] = useState(false);
   const [output, setOutput] = useState<any>();
 
@@ -79,22 +82,31 @@ export default function ComputePopup({
         throw Error("Invalid Chain");
     }
     // api call for compute
+    if (!account) throw Error("Wallet not connected");
     let headersList = {
       Accept: "*/*",
       "Content-Type": "application/json",
+      ...(await authHeaders(account, signMessageAsync)),
     };
 
+    // Comparison options are "<compute type>:<comparison>"
+    const [compute_type, comparison] = value.split(":");
     let bodyContent: {
       address: string;
       filename: string;
       compute_type: string;
+      comparison: string | undefined;
       threshold: number | undefined;
+      decimals: number | undefined;
       chain: string;
     } = {
       address: address,
       filename: file_id,
-      compute_type: value,
+      compute_type,
+      comparison,
       threshold: tval,
+      // Averages are shown with two more decimals than the data
+      decimals: compute_type === "Average" ? 2 : undefined,
       chain,
     };
     console.log(bodyContent);
@@ -160,12 +172,22 @@ export default function ComputePopup({
                     Choose Compute Type
                   </option>
                   <option value="Total">Total i.e. Sum of all values</option>
-                  <option value="GT">Total Greater Than</option>
-                  <option value="GE">Total Greater Than And Equal</option>
-                  <option value="LT">Total Less Than</option>
-                  <option value="LE">Total Less Than And Equal</option>
+                  <option value="Average">Average i.e. Mean of all values</option>
+                  <option value="Min">Minimum value</option>
+                  <option value="Max">Maximum value</option>
+                  <option value="Count">Count of values</option>
+                  <option value="Variance">Variance and Standard Deviation</option>
+                  <option value="CompareTotal:GT">Total Greater Than</option>
+                  <option value="CompareTotal:GE">Total Greater Than And Equal</option>
+                  <option value="CompareTotal:LT">Total Less Than</option>
+                  <option value="CompareTotal:LE">Total Less Than And Equal</option>
+                  <option value="CountMatching:GT">Count of values Greater Than</option>
+                  <option value="CountMatching:LT">Count of values Less Than</option>
+                  <option value="CountMatching:EQ">Count of values Equal To</option>
+                  <option value="CompareRows:GT">Each value Greater Than</option>
+                  <option value="CompareRows:LT">Each value Less Than</option>
                 </select>
-                {value == "Total" || "" ? (
+                {["", "Total", "Average", "Min", "Max", "Count", "Variance"].includes(value) ? (
                   <></>
                 ) : (
                   <div>
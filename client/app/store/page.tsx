@@ -9,16 +9,25 @@ import {
   Select,
   SelectItem,
 } from "@nextui-org/react";
-import { useAccount } from "wagmi";
+import { useAccount, useSignMessage } from "wagmi";
+import { authHeaders } from "../auth";
+
+// Poll the backup status every 5s for up to 10 minutes
+const BACKUP_POLL_INTERVAL = 5000;
+const BACKUP_POLL_ATTEMPTS = 120;
 
 const page = () => {
   const { address, isConnected } = useAccount();
+  const { signMessageAsync } = useSignMessage();
   const [value, setValue] = useState("");
   const [description, setDescription] = useState("");
   const [fileID, setFileID] = useState("");
   const [file, setFile] = useState<File | null>(null);
+  const [serverKey, setServerKey] = useState<File | null>(null);
+  const [encSymmKey, setEncSymmKey] = useState<File | null>(null);
   const [status, setStatus] = useState<0 | 1 | 2>(0);
   const [ipfsHash, setIpfsHash] = useState("");
+  const [backupState, setBackupState] = useState("");
 
   const handleSelectionChange = (e: React.ChangeEvent<HTMLSelectElement>) => {
     setValue(e.target.value);
@@ -37,28 +46,81 @@ const page = () => {
     }
   };
 
+  const handleServerKeyChange = (e: React.ChangeEvent<HTMLInputElement>) => {
+    if (e.target.files && e.target.files.length > 0) {
+      setServerKey(e.target.files[0]);
+    }
+  };
+
+  const handleEncSymmKeyChange = (e: React.ChangeEvent<HTMLInputElement>) => {
+    if (e.target.files && e.target.files.length > 0) {
+      setEncSymmKey(e.target.files[0]);
+    }
+  };
+
   const submitForm = async (e: React.FormEvent<HTMLFormElement>) => {
     e.preventDefault();
     console.log({ value, description, fileID, address });
     setStatus(1);
-    if (file) {
+    if (file && address) {
+      const auth = await authHeaders(address, signMessageAsync);
       const formData = new FormData();
       formData.append("data", file);
       formData.append("address", address?.toString() ?? "");
       formData.append("filename", value == "FHE" ? `fhe_${fileID}` : fileID);
       formData.append("description", description);
+      if (value == "FHE" && serverKey) {
+        formData.append("server_key", serverKey);
+      }
+      if (value == "AES" && encSymmKey) {
+        formData.append("enc_symm_key", encSymmKey);
+      }
       let resp = await fetch("http://localhost:8000/store", {
         method: "POST",
         body: formData,
         headers: {
           Accept: "*/*",
+          ...auth,
         },
       });
       let data = await resp.text();
-      let hash = parseResponse(data);
-      setIpfsHash(hash);
+      let datasetId = parseResponse(data);
       setStatus(2);
+      await waitForBackup(datasetId);
+    }
+  };
+
+  // The node backs datasets up in the background, poll until the CID is pinned
+  const waitForBackup = async (datasetId: string) => {
+    for (let attempt = 0; attempt < BACKUP_POLL_ATTEMPTS; attempt++) {
+      try {
+        let resp = await fetch(
+          `http://localhost:8000/dataset/${datasetId}/backup`
+        );
+        if (!resp.ok) {
+          console.error("Error fetching backup status:", resp.status);
+          setBackupState("unknown");
+          return;
+        }
+        const { backup, receipt } = await resp.json();
+        setBackupState(backup ? backup.state : "");
+        if (receipt && receipt.cid && backup?.state == "pinned") {
+          setIpfsHash(receipt.cid);
+          return;
+        }
+        if (backup?.state == "failed") {
+          return;
+        }
+      } catch (error) {
+        console.error("Error fetching backup status:", error);
+        setBackupState("unknown");
+        return;
+      }
+      await new Promise((resolve) =>
+        setTimeout(resolve, BACKUP_POLL_INTERVAL)
+      );
     }
+    setBackupState("still pending, check again later");
   };
 
   return (
@@ -114,6 +176,45 @@ const page = () => {
             onChange={handleFileChange}
           />
         </div>
+        {value == "FHE" && (
+          <div>
+            <label
+              htmlFor="server_key_upload"
+              className="block mb-2 text-md font-normal"
+            >
+              Upload FHE Server Key
+            </label>
+            <p className=" text-[14px] mb-2">
+              Note: Only needed the first time a key is used, it is the
+              fhe_server_key.b64 next to the encrypted data
+            </p>
+            <input
+              className="block w-full text-sm border border-[#A5D6FF] rounded-md cursor-pointer focus:outline-none bg-[#d0f1ff] p-2 mb-4"
+              id="server_key_upload"
+              type="file"
+              onChange={handleServerKeyChange}
+            />
+          </div>
+        )}
+        {value == "AES" && (
+          <div>
+            <label
+              htmlFor="enc_symm_key_upload"
+              className="block mb-2 text-md font-normal"
+            >
+              Upload Encrypted Symmetric Keys
+            </label>
+            <p className=" text-[14px] mb-2">
+              Note: The enc_sym_keys.b64 next to the encrypted data
+            </p>
+            <input
+              className="block w-full text-sm border border-[#A5D6FF] rounded-md cursor-pointer focus:outline-none bg-[#d0f1ff] p-2 mb-4"
+              id="enc_symm_key_upload"
+              type="file"
+              onChange={handleEncSymmKeyChange}
+            />
+          </div>
+        )}
         <div>
           <label
             htmlFor="description"
@@ -146,6 +247,9 @@ const page = () => {
         </button>
         <div>
           {" "}
+          {ipfsHash === "" && backupState !== "" && (
+            <>Backup data storage: {backupState}</>
+          )}
           {ipfsHash !== "" && (
             <>
               Backup data storage:{" "}
@@ -166,11 +270,11 @@ const page = () => {
 export default page;
 
 function parseResponse(responseText: string) {
-  // Use a regular expression to extract the Hash value
-  const hashMatch = responseText.match(/Hash: "([^"]+)"/);
-  if (hashMatch && hashMatch[1]) {
-    return hashMatch[1];
+  // The node answers with the dataset id and the queued backup job
+  const { dataset_id } = JSON.parse(responseText);
+  if (dataset_id) {
+    return dataset_id;
   } else {
-    throw new Error("Hash not found in the response");
+    throw new Error("Dataset id not found in the response");
   }
 }